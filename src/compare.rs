@@ -1,82 +1,220 @@
 use geo::{Coord, Haversine, Line, LineString, MultiLineString, Point};
-use geo::{Distance, Length};
+use geo::{Bearing, Distance, Length};
 use indicatif::ProgressBar;
 use rayon::prelude::*;
 use rstar::{PointDistance, RTree, RTreeObject, AABB};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::elevation::ElevationProfile;
+use crate::osm::OsmWay;
+use crate::pcta::AttributeValue;
+
+mod curve;
+mod offset;
+mod route;
+
+pub use curve::{assemble_paths, curve_distances, PathIndex};
+pub use offset::{estimate_section_offsets, SectionOffset};
+pub use route::{assemble_route, RouteFinding, RouteFindingKind};
 
 /// A single OSM line segment stored in the R-tree.
 #[derive(Debug, Clone)]
 pub struct IndexedSegment {
     pub line: Line<f64>,
+    /// The way this segment belongs to (ID, parent relation, node IDs and tags).
+    pub way: Arc<OsmWay>,
+    /// Position of the segment within `way`: it runs from node `segment_idx` to `segment_idx + 1`.
+    pub segment_idx: usize,
 }
 
 impl IndexedSegment {
-    pub fn new(start: Coord<f64>, end: Coord<f64>) -> Self {
+    pub fn new(way: Arc<OsmWay>, segment_idx: usize) -> Self {
+        let coords = &way.geometry.0;
         Self {
-            line: Line::new(start, end),
+            line: Line::new(coords[segment_idx], coords[segment_idx + 1]),
+            way,
+            segment_idx,
         }
     }
+
+    /// OSM node IDs at the start and end of this segment.
+    pub fn node_ids(&self) -> (u64, u64) {
+        (self.way.node_ids[self.segment_idx], self.way.node_ids[self.segment_idx + 1])
+    }
 }
 
 impl RTreeObject for IndexedSegment {
-    type Envelope = AABB<[f64; 2]>;
+    type Envelope = AABB<[f64; 3]>;
 
     fn envelope(&self) -> Self::Envelope {
-        let min_x = self.line.start.x.min(self.line.end.x);
-        let max_x = self.line.start.x.max(self.line.end.x);
-        let min_y = self.line.start.y.min(self.line.end.y);
-        let max_y = self.line.start.y.max(self.line.end.y);
-        AABB::from_corners([min_x, min_y], [max_x, max_y])
+        line_envelope(&self.line)
     }
 }
 
 impl PointDistance for IndexedSegment {
-    fn distance_2(&self, point: &[f64; 2]) -> f64 {
-        let coord = Coord {
-            x: point[0],
-            y: point[1],
-        };
-        let dist = haversine_point_to_segment(coord, self.line.start, self.line.end);
-        // rstar expects squared distance for ordering, but since we use haversine
-        // (not Euclidean), we square the result to maintain correct ordering.
-        dist * dist
+    fn distance_2(&self, point: &[f64; 3]) -> f64 {
+        line_distance_2(&self.line, point)
     }
 }
 
-/// Compute haversine distance from a point to a line segment.
-/// Projects the point onto the segment and returns distance to the closest point.
-fn haversine_point_to_segment(point: Coord<f64>, seg_start: Coord<f64>, seg_end: Coord<f64>) -> f64 {
-    let p = Point::from(point);
-    let a = Point::from(seg_start);
+/// A single PCTA line segment, indexed for the reverse (OSM → PCTA) pass.
+#[derive(Debug, Clone)]
+pub struct PctaSegment {
+    pub line: Line<f64>,
+    /// Index of the owning section in the slice passed to [`build_pcta_index`].
+    pub section_idx: usize,
+    /// Distance along the trail to the start of the segment, in meters.
+    pub trail_m: f64,
+    /// Elevation (meters) of the start and end of the segment, if the PCTA data has Z values.
+    pub elevation_m: Option<(f64, f64)>,
+}
+
+impl PctaSegment {
+    /// Distance along the trail to the point on this segment nearest `coord`.
+    pub fn trail_position(&self, coord: Coord<f64>) -> f64 {
+        let (_, t) = project_onto_segment(coord, self.line.start, self.line.end);
+        self.trail_m + t * self.line.length::<Haversine>()
+    }
+
+    /// Elevation of the point on this segment nearest `coord`, interpolated between its ends.
+    pub fn elevation_at(&self, coord: Coord<f64>) -> Option<f64> {
+        let (start, end) = self.elevation_m?;
+        let (_, t) = project_onto_segment(coord, self.line.start, self.line.end);
+        Some(start + t * (end - start))
+    }
+}
 
-    let ab_len = Line::new(seg_start, seg_end).length::<Haversine>();
+impl RTreeObject for PctaSegment {
+    type Envelope = AABB<[f64; 3]>;
 
-    if ab_len < 1e-10 {
-        return Haversine::distance(p, a);
+    fn envelope(&self) -> Self::Envelope {
+        line_envelope(&self.line)
     }
+}
+
+impl PointDistance for PctaSegment {
+    fn distance_2(&self, point: &[f64; 3]) -> f64 {
+        line_distance_2(&self.line, point)
+    }
+}
 
-    // Project point onto the line using a linear approximation in lat/lon space.
-    // This is sufficiently accurate for short segments (< ~1km).
-    let dx = seg_end.x - seg_start.x;
-    let dy = seg_end.y - seg_start.y;
-    let t = ((point.x - seg_start.x) * dx + (point.y - seg_start.y) * dy) / (dx * dx + dy * dy);
-    let t = t.clamp(0.0, 1.0);
+/// Mean earth radius in meters, as used by `geo`'s haversine measures.
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// Position of `coord` in the spatial indexes: earth-centered 3D coordinates in meters, on a
+/// sphere of the mean earth radius.
+///
+/// rstar prunes its search by comparing envelope distances with object distances, so both must
+/// be in the same metric. Straight-line (chord) distance in this space orders points the same
+/// way as great-circle distance, and works along the whole trail without projection zones.
+pub(crate) fn index_point(coord: Coord<f64>) -> [f64; 3] {
+    unit_vector(coord).map(|v| v * EARTH_RADIUS_M)
+}
+
+fn line_envelope(line: &Line<f64>) -> AABB<[f64; 3]> {
+    let (a, b) = (index_point(line.start), index_point(line.end));
+    // The great-circle arc bulges outwards from the straight line between its ends by up to
+    // its sagitta, chord² / 8R
+    let chord_2: f64 = (0..3).map(|i| (a[i] - b[i]).powi(2)).sum();
+    let bulge = chord_2 / (8.0 * EARTH_RADIUS_M) + 1e-6;
+    AABB::from_corners(
+        [0, 1, 2].map(|i| a[i].min(b[i]) - bulge),
+        [0, 1, 2].map(|i| a[i].max(b[i]) + bulge),
+    )
+}
+
+/// Squared chord distance from an [`index_point`] to the closest point on `line`'s arc.
+fn line_distance_2(line: &Line<f64>, point: &[f64; 3]) -> f64 {
+    let p = point.map(|v| v / EARTH_RADIUS_M);
+    let (closest, _) = project_onto_arc(p, unit_vector(line.start), unit_vector(line.end));
+    (0..3).map(|i| ((p[i] - closest[i]) * EARTH_RADIUS_M).powi(2)).sum()
+}
 
-    let closest = Coord {
-        x: seg_start.x + t * dx,
-        y: seg_start.y + t * dy,
+/// Compute haversine distance from a point to a line segment.
+/// Projects the point onto the segment (see [`project_onto_segment`]) and returns the
+/// great-circle distance to the closest point.
+pub(crate) fn haversine_point_to_segment(point: Coord<f64>, seg_start: Coord<f64>, seg_end: Coord<f64>) -> f64 {
+    let (closest, _) = project_onto_segment(point, seg_start, seg_end);
+    Haversine::distance(Point::from(point), Point::from(closest))
+}
+
+/// Closest point to `point` on a segment, and its position `t` (0 at the start, 1 at the end).
+///
+/// The segment is the great-circle arc between its ends, as in the haversine measures. The
+/// point is projected onto the arc's great circle (its along-track position), and clamped to the
+/// ends if it falls outside them. `t` is the fraction of the arc's length, so `t` times the
+/// haversine length of the segment is the distance along it.
+fn project_onto_segment(point: Coord<f64>, seg_start: Coord<f64>, seg_end: Coord<f64>) -> (Coord<f64>, f64) {
+    let (closest, t) = project_onto_arc(unit_vector(point), unit_vector(seg_start), unit_vector(seg_end));
+    let closest = if t == 0.0 {
+        seg_start
+    } else if t == 1.0 {
+        seg_end
+    } else {
+        from_unit_vector(closest)
     };
+    (closest, t)
+}
+
+/// [`project_onto_segment`] on unit vectors: the closest point on the arc from `a` to `b`, as a
+/// unit vector, and its position `t`.
+fn project_onto_arc(p: [f64; 3], a: [f64; 3], b: [f64; 3]) -> ([f64; 3], f64) {
+    let normal = cross(a, b);
+    let normal_len = dot(normal, normal).sqrt();
+    let arc = normal_len.atan2(dot(a, b));
+    if arc < 1e-15 {
+        return (a, 0.0);
+    }
+    let normal = normal.map(|v| v / normal_len);
+
+    // Foot of the perpendicular from `p` to the great circle, and its signed angle from `a`
+    // (positive towards `b`)
+    let cross_track = dot(p, normal);
+    let foot = [0, 1, 2].map(|i| p[i] - cross_track * normal[i]);
+    let along = dot(cross(a, foot), normal).atan2(dot(a, foot));
 
-    Haversine::distance(p, Point::from(closest))
+    let t = (along / arc).clamp(0.0, 1.0);
+    if t == 0.0 {
+        (a, t)
+    } else if t == 1.0 {
+        (b, t)
+    } else {
+        let foot_len = dot(foot, foot).sqrt();
+        (foot.map(|v| v / foot_len), t)
+    }
+}
+
+/// The point on the unit sphere at a lon/lat coordinate.
+fn unit_vector(coord: Coord<f64>) -> [f64; 3] {
+    let (lon, lat) = (coord.x.to_radians(), coord.y.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+/// The lon/lat coordinate of a (not necessarily unit) vector.
+fn from_unit_vector(v: [f64; 3]) -> Coord<f64> {
+    Coord {
+        x: v[1].atan2(v[0]).to_degrees(),
+        y: v[2].atan2(v[0].hypot(v[1])).to_degrees(),
+    }
 }
 
-/// Build an R-tree index from OSM linestrings.
-pub fn build_index(osm_lines: &[LineString<f64>], progress: Option<&ProgressBar>) -> RTree<IndexedSegment> {
-    let segments: Vec<IndexedSegment> = osm_lines
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+/// Build an R-tree index from OSM ways.
+pub fn build_index(osm_ways: &[OsmWay], progress: Option<&ProgressBar>) -> RTree<IndexedSegment> {
+    let segments: Vec<IndexedSegment> = osm_ways
         .par_iter()
-        .flat_map_iter(|ls| {
-            ls.lines()
-                .map(|line| IndexedSegment::new(line.start, line.end))
+        .flat_map_iter(|way| {
+            let way = Arc::new(way.clone());
+            (0..way.geometry.0.len().saturating_sub(1))
+                .map(move |i| IndexedSegment::new(Arc::clone(&way), i))
         })
         .collect();
     if let Some(pb) = progress {
@@ -85,31 +223,440 @@ pub fn build_index(osm_lines: &[LineString<f64>], progress: Option<&ProgressBar>
     RTree::bulk_load(segments)
 }
 
+/// OSM segments in two tiers: ways in the PCT relation, which divergences are measured
+/// against, and other trails from a larger extract, used only to explain divergences.
+pub struct TieredIndex {
+    pub relation: RTree<IndexedSegment>,
+    /// `None` when no other trails were supplied, so nothing is known about them.
+    pub other: Option<RTree<IndexedSegment>>,
+}
+
+/// Build a [`TieredIndex`] from the relation's ways and other trail ways.
+pub fn build_tiered_index(
+    relation_ways: &[OsmWay],
+    other_ways: Option<&[OsmWay]>,
+    progress: Option<&ProgressBar>,
+) -> TieredIndex {
+    TieredIndex {
+        relation: build_index(relation_ways, progress),
+        other: other_ways.map(|ways| build_index(ways, progress)),
+    }
+}
+
+/// Share of a run's samples that must lie within the threshold of a tier for the run to
+/// count as following it.
+const TIER_MATCH_FRACTION: f64 = 0.8;
+
+/// Classify each `MissingFromOsm` divergence by the OSM tier it follows: the relation, another
+/// mapped trail (recording its way IDs and categorizing it as `NotInRelation`), or neither. Only
+/// once the other trails have been checked does a `MissingFromRelation` run become `NoOsmPath`.
+pub fn classify_match_tiers(divergences: &mut [Divergence], index: &TieredIndex, options: &CompareOptions) {
+    divergences
+        .par_iter_mut()
+        .filter(|d| d.kind == DivergenceKind::MissingFromOsm)
+        .for_each(|div| {
+            let samples = sample_along(&div.geometry, options.sample_interval_m);
+            // IDs of the tier's ways within the threshold of each sample, if enough samples are
+            // within it, otherwise `None`
+            let follows = |tier: &RTree<IndexedSegment>| -> Option<Vec<u64>> {
+                let matched: Vec<u64> = samples
+                    .iter()
+                    .map(|&c| nearest_osm(tier, c))
+                    .filter(|(distance, _)| *distance <= options.threshold_m)
+                    .filter_map(|(_, way)| way.map(|w| w.id))
+                    .collect();
+                (matched.len() as f64 >= TIER_MATCH_FRACTION * samples.len() as f64).then_some(matched)
+            };
+
+            if follows(&index.relation).is_some() {
+                div.match_tier = Some(MatchTier::Relation);
+            } else if let Some(other) = &index.other {
+                if let Some(way_ids) = follows(other) {
+                    div.match_tier = Some(MatchTier::OtherPath);
+                    div.category = DivergenceCategory::NotInRelation;
+                    for id in way_ids {
+                        if !div.other_way_ids.contains(&id) {
+                            div.other_way_ids.push(id);
+                        }
+                    }
+                } else if div.category == DivergenceCategory::MissingFromRelation {
+                    div.category = DivergenceCategory::NoOsmPath;
+                }
+            }
+        });
+}
+
+/// Build an R-tree index from PCTA sections, used to find OSM ways with no PCTA counterpart.
+pub fn build_pcta_index(pcta_sections: &[PctaSection]) -> RTree<PctaSegment> {
+    let segments: Vec<PctaSegment> = pcta_sections
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(section_idx, section)| {
+            section.linestrings_with_offsets().enumerate().flat_map(move |(ls_idx, (ls, offset))| {
+                let elevations = section.elevations_m.get(ls_idx);
+                ls.lines().enumerate().scan(offset, move |trail_m, (i, line)| {
+                    let segment = PctaSegment {
+                        line,
+                        section_idx,
+                        trail_m: *trail_m,
+                        elevation_m: elevations.map(|z| (z[i], z[i + 1])),
+                    };
+                    *trail_m += line.length::<Haversine>();
+                    Some(segment)
+                })
+            })
+        })
+        .collect();
+    RTree::bulk_load(segments)
+}
+
 /// A section of the PCTA trail with its name and geometry.
 #[derive(Debug, Clone)]
 pub struct PctaSection {
     pub section_name: String,
+    pub state: Option<String>,
+    /// Whether this is the official route rather than an alternate, if the source says.
+    pub official: Option<bool>,
+    /// The source's ID for the section or segment, if it has one.
+    pub segment_id: Option<String>,
+    /// Every attribute of the source feature, by field name.
+    pub attributes: BTreeMap<String, AttributeValue>,
     pub geometry: MultiLineString<f64>,
+    /// Elevation (meters) of each vertex of `geometry`, one list per linestring; empty if the
+    /// source has no Z values.
+    pub elevations_m: Vec<Vec<f64>>,
+    /// Distance along the full trail to the start of this section, in meters. Set by
+    /// [`assemble_route`].
+    pub start_m: f64,
+    /// Distance along the full trail to the start of each linestring of `geometry`, in meters,
+    /// since other sections may come between them. Set by [`assemble_route`]; if empty, the
+    /// linestrings are taken to follow on from `start_m`.
+    pub linestring_starts_m: Vec<f64>,
+}
+
+impl PctaSection {
+    /// Each linestring of the section with the distance along the trail to its start.
+    fn linestrings_with_offsets(&self) -> impl Iterator<Item = (&LineString<f64>, f64)> {
+        let starts = (self.linestring_starts_m.len() == self.geometry.0.len()).then_some(&self.linestring_starts_m);
+        self.geometry.0.iter().enumerate().scan(self.start_m, move |offset, (i, ls)| {
+            let start = starts.map_or(*offset, |starts| starts[i]);
+            *offset = start + ls.length::<Haversine>();
+            Some((ls, start))
+        })
+    }
+}
+
+/// Meters per statute mile.
+pub const METERS_PER_MILE: f64 = 1609.344;
+
+/// How samples are scored against the other dataset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompareMode {
+    /// Distance from each sample to the nearest segment.
+    #[default]
+    Nearest,
+    /// Discrete Fréchet distance between a window of samples around each one and the
+    /// corresponding stretch of the other dataset, so a line that only passes nearby from
+    /// another direction (a switchback, a crossing) doesn't count as a match.
+    Frechet,
+}
+
+/// Thresholds and sampling settings for the comparison.
+#[derive(Debug, Clone, Copy)]
+pub struct CompareOptions {
+    /// Minimum distance (meters) to count as divergence.
+    pub threshold_m: f64,
+    /// Distance (meters) a divergent run must fall back to before it ends, if lower than
+    /// `threshold_m`: runs start above `threshold_m` but only end below this, so a run that
+    /// briefly dips under the threshold isn't split.
+    pub exit_threshold_m: Option<f64>,
+    /// Divergent runs separated by at most this many meters are merged into one.
+    pub max_gap_m: f64,
+    /// Segments within this distance (meters) of a sample are candidates for its match.
+    pub search_radius_m: f64,
+    /// Minimum divergent run length (meters).
+    pub min_length_m: f64,
+    /// Distance between sample points (meters).
+    pub sample_interval_m: f64,
+    pub mode: CompareMode,
+    /// Length of the window compared around each sample in [`CompareMode::Frechet`] (meters).
+    pub frechet_window_m: f64,
+    /// Keep every sample in [`Comparison::profile`].
+    pub profile: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        Self {
+            threshold_m: 10.0,
+            exit_threshold_m: None,
+            max_gap_m: 0.0,
+            search_radius_m: 50.0,
+            min_length_m: 500.0,
+            sample_interval_m: 25.0,
+            mode: CompareMode::Nearest,
+            frechet_window_m: 200.0,
+            profile: false,
+        }
+    }
+}
+
+impl CompareOptions {
+    /// The distance below which a divergent run ends.
+    fn exit_threshold_m(&self) -> f64 {
+        self.exit_threshold_m.map_or(self.threshold_m, |exit| exit.min(self.threshold_m))
+    }
+
+    /// Vertex spacing for the discrete curve measures: fine enough that discretization error
+    /// stays well under the threshold, coarse enough to bound the cost on long runs.
+    fn curve_spacing_m(&self, length_m: f64) -> f64 {
+        (self.threshold_m / 4.0).min(self.sample_interval_m).max(1.0).max(length_m / 1000.0)
+    }
+}
+
+/// Which side of the comparison a divergence was found on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// PCTA geometry with no nearby OSM way: trail to add to the relation.
+    MissingFromOsm,
+    /// OSM way with no nearby PCTA geometry: likely an old alignment to remove from the relation.
+    StaleOsmWay,
+}
+
+impl DivergenceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DivergenceKind::MissingFromOsm => "missing_from_osm",
+            DivergenceKind::StaleOsmWay => "stale_osm_way",
+        }
+    }
+}
+
+impl std::str::FromStr for DivergenceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "missing_from_osm" => Ok(DivergenceKind::MissingFromOsm),
+            "stale_osm_way" => Ok(DivergenceKind::StaleOsmWay),
+            _ => anyhow::bail!("Unknown divergence kind `{}`", s),
+        }
+    }
 }
 
 /// A detected divergence between the PCTA and OSM data.
 #[derive(Debug, Clone)]
 pub struct Divergence {
-    pub pcta_segment: LineString<f64>,
+    /// Stable identifier, see [`divergence_id`].
+    pub id: String,
+    pub kind: DivergenceKind,
+    /// The divergent run: PCTA geometry for `MissingFromOsm`, OSM geometry for `StaleOsmWay`.
+    pub geometry: LineString<f64>,
+    /// The PCTA section the divergence belongs to (nearest section for `StaleOsmWay`), and its
+    /// state, official status, segment ID and source attributes.
     pub section_name: String,
+    pub state: Option<String>,
+    pub official: Option<bool>,
+    pub segment_id: Option<String>,
+    pub pcta_attributes: BTreeMap<String, AttributeValue>,
+    /// Distance along the PCTA centerline from the Mexican border to the start and end of the
+    /// divergence, in meters. For `StaleOsmWay`, measured at the nearest PCTA points.
+    pub start_m: f64,
+    pub end_m: f64,
+    /// Max and mean of the per-sample score: nearest distance, or windowed Fréchet distance
+    /// in [`CompareMode::Frechet`].
     pub max_distance_m: f64,
     pub mean_distance_m: f64,
+    /// Discrete Fréchet and Hausdorff distances between the whole run and the stretch of the
+    /// other dataset it corresponds to.
+    pub frechet_m: f64,
+    pub hausdorff_m: f64,
     pub length_m: f64,
+    /// OSM ways nearest to the run (the way itself for `StaleOsmWay`), in order along the run.
+    pub osm_way_ids: Vec<u64>,
+    /// Relations those ways were fetched through, deduplicated.
+    pub osm_relation_ids: Vec<u64>,
+    /// Which OSM tier the divergent PCTA run follows, if any. Only set for `MissingFromOsm`
+    /// divergences, by [`classify_match_tiers`].
+    pub match_tier: Option<MatchTier>,
+    /// Ways outside the relation that the run follows, when `match_tier` is `OtherPath`.
+    pub other_way_ids: Vec<u64>,
+    /// What the divergence most likely is, from its distance profile and, for `MissingFromOsm`,
+    /// the tier it follows.
+    pub category: DivergenceCategory,
+    /// Climbing along the run, set by [`crate::elevation::annotate_elevations`].
+    pub elevation: Option<ElevationProfile>,
+    /// Climbing along the stretch of the other dataset the run corresponds to (the old trail,
+    /// for `MissingFromOsm`), so a reroute can be compared with what it replaces.
+    pub counterpart_elevation: Option<ElevationProfile>,
+}
+
+/// One sample of a comparison pass, for plotting the distance profile along the trail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileSample {
+    pub kind: DivergenceKind,
+    pub section_name: String,
+    /// Distance along the trail in meters (for `StaleOsmWay`, of the matched PCTA point).
+    pub trail_m: f64,
+    pub coord: Coord<f64>,
+    /// Distance to the other dataset in meters (the windowed Fréchet distance in
+    /// [`CompareMode::Frechet`]), or `None` if it is empty.
+    pub distance_m: Option<f64>,
+    /// The matched OSM way (for `StaleOsmWay`, the way being sampled).
+    pub osm_way_id: Option<u64>,
+    pub bearing_diff_deg: Option<f64>,
+    /// Whether the sample is part of a reported divergence.
+    pub divergent: bool,
+}
+
+/// The result of a comparison pass.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    pub divergences: Vec<Divergence>,
+    /// Every sample, in order along each compared linestring, if [`CompareOptions::profile`]
+    /// is set.
+    pub profile: Vec<ProfileSample>,
+}
+
+impl Comparison {
+    fn append(mut self, mut other: Comparison) -> Self {
+        self.divergences.append(&mut other.divergences);
+        self.profile.append(&mut other.profile);
+        self
+    }
+}
+
+/// What a divergence most likely is, for triage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivergenceCategory {
+    /// The PCTA follows an OSM trail that isn't in the relation (needs `--other-paths`).
+    NotInRelation,
+    /// The relation has nothing corresponding to the run, and other OSM trails weren't checked.
+    MissingFromRelation,
+    /// Neither the relation nor the other OSM trails have anything corresponding to the run.
+    NoOsmPath,
+    /// Both datasets follow the same course at a small, steady offset: likely misaligned
+    /// imagery or GPS traces rather than a real change.
+    ParallelOffset,
+    /// The datasets take different courses between the run's ends.
+    Reroute,
+}
+
+impl DivergenceCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DivergenceCategory::NotInRelation => "not_in_relation",
+            DivergenceCategory::MissingFromRelation => "missing_from_relation",
+            DivergenceCategory::NoOsmPath => "no_osm_path",
+            DivergenceCategory::ParallelOffset => "parallel_offset",
+            DivergenceCategory::Reroute => "reroute",
+        }
+    }
+}
+
+impl std::str::FromStr for DivergenceCategory {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "not_in_relation" => Ok(DivergenceCategory::NotInRelation),
+            "missing_from_relation" => Ok(DivergenceCategory::MissingFromRelation),
+            "no_osm_path" => Ok(DivergenceCategory::NoOsmPath),
+            "parallel_offset" => Ok(DivergenceCategory::ParallelOffset),
+            "reroute" => Ok(DivergenceCategory::Reroute),
+            _ => anyhow::bail!("Unknown divergence category `{}`", s),
+        }
+    }
+}
+
+/// Largest mean offset (meters) still treated as misalignment rather than a separate trail.
+const PARALLEL_MAX_OFFSET_M: f64 = 75.0;
+/// Largest coefficient of variation of a run's distances for it to count as a steady offset.
+const PARALLEL_MAX_VARIATION: f64 = 0.25;
+/// How far the matching stretch's length may differ from the run's for a parallel offset.
+const PARALLEL_LENGTH_TOLERANCE: f64 = 0.15;
+/// A matching stretch shorter than this fraction of the run means there is nothing to match.
+const NO_PATH_LENGTH_RATIO: f64 = 0.2;
+
+/// Categorize a run from its distance profile and the length of the matching stretch of the
+/// other dataset. `NotInRelation` and `NoOsmPath` need the other OSM trails and are set by
+/// [`classify_match_tiers`].
+fn categorize(kind: DivergenceKind, run: &[Sample], length_m: f64, counterpart_m: Option<f64>) -> DivergenceCategory {
+    let distances: Vec<f64> = run.iter().map(|s| s.distance_m).filter(|&d| d < f64::MAX).collect();
+    let mean = distances.iter().sum::<f64>() / distances.len().max(1) as f64;
+    let variance = distances.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / distances.len().max(1) as f64;
+    let steady = !distances.is_empty() && variance.sqrt() <= PARALLEL_MAX_VARIATION * mean;
+
+    let length_ratio = counterpart_m.map_or(0.0, |c| c / length_m);
+    if steady && mean <= PARALLEL_MAX_OFFSET_M && (length_ratio - 1.0).abs() <= PARALLEL_LENGTH_TOLERANCE {
+        DivergenceCategory::ParallelOffset
+    } else if kind == DivergenceKind::MissingFromOsm && length_ratio < NO_PATH_LENGTH_RATIO {
+        DivergenceCategory::MissingFromRelation
+    } else {
+        DivergenceCategory::Reroute
+    }
+}
+
+/// The tier of OSM ways a divergent PCTA run was found to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchTier {
+    /// Ways in the PCT relation (possible when the run was only flagged by its shape, in
+    /// [`CompareMode::Frechet`]).
+    Relation,
+    /// An existing OSM trail that isn't in the PCT relation: add it to the relation.
+    OtherPath,
+}
+
+impl MatchTier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchTier::Relation => "relation",
+            MatchTier::OtherPath => "other_path",
+        }
+    }
+}
+
+impl std::str::FromStr for MatchTier {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "relation" => Ok(MatchTier::Relation),
+            "other_path" => Ok(MatchTier::OtherPath),
+            _ => anyhow::bail!("Unknown match tier `{}`", s),
+        }
+    }
+}
+
+/// A sampled point, its distance to the other dataset, the OSM way involved and its
+/// position along the trail.
+#[derive(Debug, Clone, Copy)]
+struct Sample<'a> {
+    coord: Coord<f64>,
+    distance_m: f64,
+    way: Option<&'a OsmWay>,
+    trail_m: f64,
+    /// Difference between the sample's heading and its match's, if known (see [`match_segment`]).
+    bearing_diff_deg: Option<f64>,
 }
 
 /// Sample points along a linestring at regular intervals using haversine interpolation.
-fn sample_along(ls: &LineString<f64>, interval_m: f64) -> Vec<Coord<f64>> {
+pub(crate) fn sample_along(ls: &LineString<f64>, interval_m: f64) -> Vec<Coord<f64>> {
+    sample_along_with_positions(ls, interval_m)
+        .into_iter()
+        .map(|(coord, _)| coord)
+        .collect()
+}
+
+/// Like [`sample_along`], paired with each sample's distance along `ls` in meters.
+fn sample_along_with_positions(ls: &LineString<f64>, interval_m: f64) -> Vec<(Coord<f64>, f64)> {
     if ls.0.len() < 2 {
-        return ls.0.clone();
+        return ls.0.iter().map(|&c| (c, 0.0)).collect();
     }
 
-    let mut samples = vec![ls.0[0]];
+    let mut samples = vec![(ls.0[0], 0.0)];
     let mut remaining = interval_m;
+    let mut travelled = 0.0;
 
     for line in ls.lines() {
         let seg_len = Haversine::distance(Point::from(line.start), Point::from(line.end));
@@ -124,134 +671,565 @@ fn sample_along(ls: &LineString<f64>, interval_m: f64) -> Vec<Coord<f64>> {
                 x: line.start.x + t * (line.end.x - line.start.x),
                 y: line.start.y + t * (line.end.y - line.start.y),
             };
-            samples.push(coord);
+            samples.push((coord, travelled + offset));
             offset += interval_m;
         }
         remaining = offset - seg_len;
+        travelled += seg_len;
     }
 
     // Always include the last point
-    if let Some(&last) = ls.0.last() {
-        if samples.last() != Some(&last) {
-            samples.push(last);
-        }
+    if let Some(&last) = ls.0.last()
+        && samples.last().map(|(c, _)| *c) != Some(last)
+    {
+        samples.push((last, travelled));
     }
 
     samples
 }
 
+/// The segment in `index` nearest `coord`.
+pub(crate) fn nearest<T>(index: &RTree<T>, coord: Coord<f64>) -> Option<&T>
+where
+    T: PointDistance + RTreeObject<Envelope = AABB<[f64; 3]>>,
+{
+    index.nearest_neighbor(&index_point(coord))
+}
+
+/// Distance in meters from `coord` to the nearest OSM segment and that segment's way,
+/// or `f64::MAX` if the index is empty.
+fn nearest_osm(osm_index: &RTree<IndexedSegment>, coord: Coord<f64>) -> (f64, Option<&OsmWay>) {
+    nearest(osm_index, coord).map_or((f64::MAX, None), |seg| {
+        (
+            haversine_point_to_segment(coord, seg.line.start, seg.line.end),
+            Some(seg.way.as_ref()),
+        )
+    })
+}
+
+/// Nearest PCTA segment to `coord` and its distance in meters.
+fn nearest_pcta(pcta_index: &RTree<PctaSegment>, coord: Coord<f64>) -> Option<(&PctaSegment, f64)> {
+    nearest(pcta_index, coord).map(|seg| (seg, haversine_point_to_segment(coord, seg.line.start, seg.line.end)))
+}
+
+/// A segment in one of the spatial indexes that samples are matched against.
+trait MatchSegment: PointDistance + RTreeObject<Envelope = AABB<[f64; 3]>> {
+    fn line(&self) -> &Line<f64>;
+
+    /// Whether this segment is, or joins on to, `previous`.
+    fn continues(&self, previous: &Self) -> bool {
+        let (a, b) = (self.line(), previous.line());
+        a.start == b.start || a.start == b.end || a.end == b.start || a.end == b.end
+    }
+}
+
+impl MatchSegment for IndexedSegment {
+    fn line(&self) -> &Line<f64> {
+        &self.line
+    }
+}
+
+impl MatchSegment for PctaSegment {
+    fn line(&self) -> &Line<f64> {
+        &self.line
+    }
+}
+
+/// Cost (meters) added to a candidate running perpendicular to the sample's heading; scaled by
+/// the sine of the bearing difference.
+const HEADING_WEIGHT_M: f64 = 25.0;
+/// Cost (meters) added to a candidate that doesn't join on to the previous sample's match.
+const CONTINUITY_PENALTY_M: f64 = 10.0;
+
+/// The segment a sample was matched to.
+struct Match<'t, T> {
+    segment: &'t T,
+    distance_m: f64,
+    /// Undirected difference (degrees, 0-90) between the sample's heading and the segment's.
+    bearing_diff_deg: Option<f64>,
+}
+
+/// Match a sample to a segment of `index`.
+///
+/// The nearest segment is often on the wrong leg at junctions and switchbacks, so every
+/// segment within `options.search_radius_m` is a candidate, costed by its distance plus
+/// penalties for disagreeing with the sample's `heading` (degrees) and for not joining on to
+/// `previous`, the previous sample's match. Beyond the radius, the nearest segment is used.
+fn match_segment<'t, T: MatchSegment>(
+    index: &'t RTree<T>,
+    coord: Coord<f64>,
+    heading: Option<f64>,
+    previous: Option<&T>,
+    options: &CompareOptions,
+) -> Option<Match<'t, T>> {
+    let evaluate = |segment: &'t T| {
+        let line = segment.line();
+        let distance_m = haversine_point_to_segment(coord, line.start, line.end);
+        let bearing_diff_deg = heading.map(|heading| {
+            let bearing = Haversine::bearing(Point::from(line.start), Point::from(line.end));
+            let diff = (heading - bearing).rem_euclid(180.0);
+            diff.min(180.0 - diff)
+        });
+        let cost = distance_m
+            + bearing_diff_deg.map_or(0.0, |diff| HEADING_WEIGHT_M * diff.to_radians().sin())
+            + if previous.is_some_and(|previous| !segment.continues(previous)) {
+                CONTINUITY_PENALTY_M
+            } else {
+                0.0
+            };
+        (
+            Match {
+                segment,
+                distance_m,
+                bearing_diff_deg,
+            },
+            cost,
+        )
+    };
+
+    let radius_m = options.search_radius_m;
+    index
+        .locate_within_distance(index_point(coord), radius_m * radius_m)
+        .map(evaluate)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .or_else(|| nearest(index, coord).map(evaluate))
+        .map(|(m, _)| m)
+}
+
 /// Process a single linestring to find divergent segments.
-fn process_linestring(
+///
+/// `measure` is given each sample point, its distance along `ls` and the heading of `ls` there,
+/// in order, and measures how far the sample lies from the other dataset, which OSM way the
+/// measurement involved and where the sample is along the trail. `counterpart` holds the other
+/// dataset as ordered paths, for the curve measures.
+fn process_linestring<'a>(
     ls: &LineString<f64>,
-    section_name: &str,
-    osm_index: &RTree<IndexedSegment>,
-    threshold_m: f64,
-    min_length_m: f64,
-    sample_interval_m: f64,
-) -> Vec<Divergence> {
-    let samples = sample_along(ls, sample_interval_m);
+    section: &PctaSection,
+    kind: DivergenceKind,
+    mut measure: impl FnMut(Coord<f64>, f64, Option<f64>) -> Sample<'a>,
+    counterpart: &PathIndex,
+    options: &CompareOptions,
+) -> Comparison {
+    let threshold_m = options.threshold_m;
+    let samples = sample_along_with_positions(ls, options.sample_interval_m);
     if samples.is_empty() {
-        return Vec::new();
+        return Comparison::default();
     }
 
-    // Compute distances for each sample in parallel
-    let distances: Vec<(Coord<f64>, f64)> = samples
-        .par_iter()
-        .map(|&coord| {
-            let point = [coord.x, coord.y];
-            let dist = osm_index
-                .nearest_neighbor(&point)
-                .map(|seg| haversine_point_to_segment(coord, seg.line.start, seg.line.end))
-                .unwrap_or(f64::MAX);
-            (coord, dist)
+    // Measured in order, since each match depends on the previous one
+    let mut distances: Vec<Sample> = (0..samples.len())
+        .map(|i| {
+            let (before, after) = (samples[i.saturating_sub(1)].0, samples[(i + 1).min(samples.len() - 1)].0);
+            let heading = (before != after).then(|| Haversine::bearing(Point::from(before), Point::from(after)));
+            measure(samples[i].0, samples[i].1, heading)
         })
         .collect();
 
-    // State machine to detect contiguous divergent runs (sequential — order-dependent)
-    let mut divergences = Vec::new();
-    let mut run_start: Option<usize> = None;
+    if options.mode == CompareMode::Frechet {
+        let half = (options.frechet_window_m / 2.0 / options.sample_interval_m).ceil() as usize;
+        let spacing_m = options.curve_spacing_m(options.frechet_window_m);
+        let scores: Vec<f64> = (0..distances.len())
+            .into_par_iter()
+            .map(|i| {
+                let window = &distances[i.saturating_sub(half)..(i + half + 1).min(distances.len())];
+                let coords: Vec<Coord<f64>> = window.iter().map(|s| s.coord).collect();
+                counterpart
+                    .counterpart(&coords)
+                    .map_or(f64::MAX, |other| curve_distances(&LineString::from(coords), &other, spacing_m).0)
+            })
+            .collect();
+        for (sample, score) in distances.iter_mut().zip(scores) {
+            sample.distance_m = score;
+        }
+    }
 
-    for i in 0..=distances.len() {
-        let divergent = i < distances.len() && distances[i].1 > threshold_m;
+    // Runs are stretches above the exit threshold that reach the (entry) threshold somewhere
+    let exit_m = options.exit_threshold_m();
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < distances.len() {
+        if distances[i].distance_m <= exit_m {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < distances.len() && distances[i].distance_m > exit_m {
+            i += 1;
+        }
+        if distances[start..i].iter().any(|s| s.distance_m > threshold_m) {
+            runs.push((start, i));
+        }
+    }
 
-        if divergent && run_start.is_none() {
-            run_start = Some(i);
-        } else if !divergent && run_start.is_some() {
-            let start = run_start.unwrap();
-            let run = &distances[start..i];
-            emit_divergence(run, section_name, min_length_m, &mut divergences);
-            run_start = None;
+    // Position along `ls` where the distance crosses the exit threshold between two samples
+    let crossing_m = |within: usize, divergent: usize| {
+        let (a, b) = (&distances[within], &distances[divergent]);
+        let t = ((exit_m - a.distance_m) / (b.distance_m - a.distance_m)).clamp(0.0, 1.0);
+        samples[within].1 + t * (samples[divergent].1 - samples[within].1)
+    };
+    // Merge runs separated by short gaps, such as where a reroute crosses the old alignment
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in runs {
+        match merged.last_mut() {
+            Some(last) if crossing_m(start - 1, start) - crossing_m(last.1, last.1 - 1) <= options.max_gap_m => {
+                last.1 = end;
+            }
+            _ => merged.push((start, end)),
         }
     }
 
-    divergences
+    let mut divergences = Vec::new();
+    let mut divergent = vec![false; distances.len()];
+    for (start, end) in merged {
+        let run = &distances[start..end];
+        // Locate the run's ends where the distance crosses the threshold, rather than at the
+        // first and last samples, so they don't move with the sample interval
+        let first = match start.checked_sub(1) {
+            Some(prev) => threshold_crossing(&distances[prev], &run[0], exit_m),
+            None => run[0],
+        };
+        let last = match distances.get(end) {
+            Some(next) => threshold_crossing(next, &run[run.len() - 1], exit_m),
+            None => run[run.len() - 1],
+        };
+        let emitted = divergences.len();
+        emit_divergence(run, (first, last), section, kind, counterpart, options, &mut divergences);
+        if divergences.len() > emitted {
+            divergent[start..end].fill(true);
+        }
+    }
+
+    let profile = if options.profile {
+        distances
+            .iter()
+            .zip(divergent)
+            .map(|(sample, divergent)| ProfileSample {
+                kind,
+                section_name: section.section_name.clone(),
+                trail_m: sample.trail_m,
+                coord: sample.coord,
+                distance_m: (sample.distance_m < f64::MAX).then_some(sample.distance_m),
+                osm_way_id: sample.way.map(|w| w.id),
+                bearing_diff_deg: sample.bearing_diff_deg,
+                divergent,
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    Comparison { divergences, profile }
 }
 
 /// Find divergent segments between PCTA sections and the OSM index.
+///
+/// `osm_paths` holds the same OSM ways joined into ordered paths (see [`assemble_paths`]).
+/// With `section_offsets` (from [`estimate_section_offsets`], in the same order as
+/// `pcta_sections`), each section is moved by its offset before comparing, if the offset is
+/// reliable; divergences keep the original PCTA geometry.
 pub fn find_divergences(
     pcta_sections: &[PctaSection],
     osm_index: &RTree<IndexedSegment>,
-    threshold_m: f64,
-    min_length_m: f64,
-    sample_interval_m: f64,
+    osm_paths: &PathIndex,
+    options: &CompareOptions,
+    section_offsets: Option<&[SectionOffset]>,
     progress: Option<&ProgressBar>,
-) -> Vec<Divergence> {
+) -> Comparison {
     pcta_sections
         .par_iter()
-        .flat_map_iter(|section| {
-            let divs: Vec<Divergence> = section
-                .geometry
-                .0
-                .iter()
-                .flat_map(|ls| {
+        .enumerate()
+        .map(|(section_idx, section)| {
+            let offset = section_offsets
+                .and_then(|offsets| offsets.get(section_idx))
+                .filter(|offset| offset.is_reliable());
+            let moved = offset.map(|offset| offset.apply_to_section(section));
+            let section = moved.as_ref().unwrap_or(section);
+
+            let mut comparison = section
+                .linestrings_with_offsets()
+                .map(|(ls, offset)| {
+                    let mut previous = None;
                     process_linestring(
                         ls,
-                        &section.section_name,
-                        osm_index,
-                        threshold_m,
-                        min_length_m,
-                        sample_interval_m,
+                        section,
+                        DivergenceKind::MissingFromOsm,
+                        |coord, along_m, heading| {
+                            let matched = match_segment(osm_index, coord, heading, previous, options);
+                            previous = matched.as_ref().map(|m| m.segment);
+                            Sample {
+                                coord,
+                                distance_m: matched.as_ref().map_or(f64::MAX, |m| m.distance_m),
+                                way: matched.as_ref().map(|m| m.segment.way.as_ref()),
+                                trail_m: offset + along_m,
+                                bearing_diff_deg: matched.and_then(|m| m.bearing_diff_deg),
+                            }
+                        },
+                        osm_paths,
+                        options,
                     )
                 })
-                .collect();
+                .fold(Comparison::default(), Comparison::append);
+            if let Some(offset) = offset {
+                for div in &mut comparison.divergences {
+                    div.geometry.0.iter_mut().for_each(|c| *c = offset.remove(*c));
+                }
+                for sample in &mut comparison.profile {
+                    sample.coord = offset.remove(sample.coord);
+                }
+            }
             if let Some(pb) = progress {
                 pb.inc(1);
             }
-            divs
+            comparison
         })
-        .collect()
+        .reduce(Comparison::default, Comparison::append)
+}
+
+/// Find OSM ways (or parts of them) with no PCTA geometry nearby.
+///
+/// This is the reverse of [`find_divergences`]: OSM linestrings are sampled against the
+/// PCTA index, so old alignments still in the relation are reported as `StaleOsmWay`.
+/// `pcta_paths` holds the PCTA geometry as ordered paths ([`PathIndex::from_sections`]).
+pub fn find_stale_osm_ways(
+    osm_ways: &[OsmWay],
+    pcta_sections: &[PctaSection],
+    pcta_index: &RTree<PctaSegment>,
+    pcta_paths: &PathIndex,
+    options: &CompareOptions,
+    progress: Option<&ProgressBar>,
+) -> Comparison {
+    let unknown = PctaSection {
+        section_name: "Unknown".to_string(),
+        state: None,
+        official: None,
+        segment_id: None,
+        attributes: BTreeMap::new(),
+        geometry: MultiLineString::new(Vec::new()),
+        elevations_m: Vec::new(),
+        start_m: 0.0,
+        linestring_starts_m: Vec::new(),
+    };
+    osm_ways
+        .par_iter()
+        .map(|way| {
+            let ls = &way.geometry;
+            // Attribute the way to whichever PCTA section is closest to its midpoint
+            let section = ls
+                .0
+                .get(ls.0.len() / 2)
+                .and_then(|&midpoint| nearest_pcta(pcta_index, midpoint))
+                .map_or(&unknown, |(seg, _)| &pcta_sections[seg.section_idx]);
+
+            let mut previous = None;
+            let comparison = process_linestring(
+                ls,
+                section,
+                DivergenceKind::StaleOsmWay,
+                |coord, _, heading| {
+                    let matched = match_segment(pcta_index, coord, heading, previous, options);
+                    previous = matched.as_ref().map(|m| m.segment);
+                    Sample {
+                        coord,
+                        distance_m: matched.as_ref().map_or(f64::MAX, |m| m.distance_m),
+                        way: Some(way),
+                        trail_m: matched.as_ref().map_or(0.0, |m| m.segment.trail_position(coord)),
+                        bearing_diff_deg: matched.and_then(|m| m.bearing_diff_deg),
+                    }
+                },
+                pcta_paths,
+                options,
+            );
+            if let Some(pb) = progress {
+                pb.inc(1);
+            }
+            comparison
+        })
+        .reduce(Comparison::default, Comparison::append)
+}
+
+/// Interpolate where the distance crosses `threshold_m` between a sample within the threshold
+/// and a neighbouring divergent one.
+fn threshold_crossing<'a>(within: &Sample<'a>, divergent: &Sample<'a>, threshold_m: f64) -> Sample<'a> {
+    let t = ((threshold_m - within.distance_m) / (divergent.distance_m - within.distance_m)).clamp(0.0, 1.0);
+    Sample {
+        coord: Coord {
+            x: within.coord.x + t * (divergent.coord.x - within.coord.x),
+            y: within.coord.y + t * (divergent.coord.y - within.coord.y),
+        },
+        distance_m: threshold_m,
+        way: divergent.way,
+        trail_m: within.trail_m + t * (divergent.trail_m - within.trail_m),
+        bearing_diff_deg: divergent.bearing_diff_deg,
+    }
 }
 
+/// Divergence ends are rounded to this many meters along the section before hashing.
+const ID_QUANTUM_M: f64 = 100.0;
+
+/// Distance in meters along `section` to the point nearest `coord`, counting its linestrings
+/// in order. `None` if the section has no geometry.
+fn position_along_section(section: &PctaSection, coord: Coord<f64>) -> Option<f64> {
+    let mut offset = 0.0;
+    let mut best: Option<(f64, f64)> = None;
+    for ls in &section.geometry.0 {
+        for line in ls.lines() {
+            let (closest, t) = project_onto_segment(coord, line.start, line.end);
+            let distance = Haversine::distance(Point::from(coord), Point::from(closest));
+            let seg_len = line.length::<Haversine>();
+            if best.is_none_or(|(d, _)| distance < d) {
+                best = Some((distance, offset + t * seg_len));
+            }
+            offset += seg_len;
+        }
+    }
+    best.map(|(_, position)| position)
+}
+
+/// A stable identifier for a divergence: a hash of its kind, section and the positions of its
+/// ends along the section, rounded to [`ID_QUANTUM_M`]. The same divergence keeps its ID
+/// between runs unless an end moves across a rounding boundary.
+///
+/// Stale OSM ways beside the same stretch of trail project onto the same positions, so their
+/// `osm_way_ids` are part of the hash too.
+pub fn divergence_id(
+    kind: DivergenceKind,
+    section: &PctaSection,
+    start: Coord<f64>,
+    end: Coord<f64>,
+    osm_way_ids: &[u64],
+) -> String {
+    let quantize = |position: f64| (position / ID_QUANTUM_M).round() as i64;
+    let location = match (position_along_section(section, start), position_along_section(section, end)) {
+        (Some(a), Some(b)) => {
+            // Reverse-pass runs follow the OSM way, which may point either way along the section
+            let (a, b) = (quantize(a), quantize(b));
+            format!("{}-{}", a.min(b), a.max(b))
+        }
+        // No section geometry to measure along: fall back to rounded coordinates (~100m)
+        _ => format!("{:.3},{:.3}-{:.3},{:.3}", start.x, start.y, end.x, end.y),
+    };
+    let mut key = format!("{}|{}|{}", kind.as_str(), section.section_name, location);
+    if kind == DivergenceKind::StaleOsmWay {
+        let mut way_ids = osm_way_ids.to_vec();
+        way_ids.sort_unstable();
+        for id in way_ids {
+            key.push_str(&format!("|{}", id));
+        }
+    }
+    format!("{:016x}", fnv1a(key.as_bytes()))
+}
+
+/// 64-bit FNV-1a, used instead of `DefaultHasher` because its output must not change between
+/// Rust releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Emit a divergence for `run`, whose ends (interpolated to the threshold) are `first` and `last`.
 fn emit_divergence(
-    run: &[(Coord<f64>, f64)],
-    section_name: &str,
-    min_length_m: f64,
+    run: &[Sample],
+    (first, last): (Sample, Sample),
+    section: &PctaSection,
+    kind: DivergenceKind,
+    counterpart: &PathIndex,
+    options: &CompareOptions,
     divergences: &mut Vec<Divergence>,
 ) {
-    let coords: Vec<Coord<f64>> = run.iter().map(|(c, _)| *c).collect();
+    let coords: Vec<Coord<f64>> = run.iter().map(|s| s.coord).collect();
     let ls = LineString::from(coords);
     let length = ls.length::<Haversine>();
 
-    if length < min_length_m {
+    if length < options.min_length_m {
         return;
     }
 
-    let max_distance_m = run.iter().map(|(_, d)| *d).fold(0.0_f64, f64::max);
-    let mean_distance_m = run.iter().map(|(_, d)| *d).sum::<f64>() / run.len() as f64;
+    let other = counterpart.counterpart(&ls.0);
+    let (frechet_m, hausdorff_m) = other.as_ref().map_or((f64::MAX, f64::MAX), |other| {
+        curve_distances(&ls, other, options.curve_spacing_m(length))
+    });
+    let category = categorize(kind, run, length, other.map(|o| o.length::<Haversine>()));
+
+    let max_distance_m = run.iter().map(|s| s.distance_m).fold(0.0_f64, f64::max);
+    let mean_distance_m = run.iter().map(|s| s.distance_m).sum::<f64>() / run.len() as f64;
+
+    let mut osm_way_ids: Vec<u64> = Vec::new();
+    let mut osm_relation_ids: Vec<u64> = Vec::new();
+    for way in run.iter().filter_map(|s| s.way) {
+        if !osm_way_ids.contains(&way.id) {
+            osm_way_ids.push(way.id);
+        }
+        if let Some(relation_id) = way.relation_id()
+            && !osm_relation_ids.contains(&relation_id)
+        {
+            osm_relation_ids.push(relation_id);
+        }
+    }
 
     divergences.push(Divergence {
-        pcta_segment: ls,
-        section_name: section_name.to_string(),
+        id: divergence_id(kind, section, first.coord, last.coord, &osm_way_ids),
+        kind,
+        geometry: ls,
+        section_name: section.section_name.clone(),
+        state: section.state.clone(),
+        official: section.official,
+        segment_id: section.segment_id.clone(),
+        pcta_attributes: section.attributes.clone(),
+        // OSM ways may run either way along the trail
+        start_m: first.trail_m.min(last.trail_m),
+        end_m: first.trail_m.max(last.trail_m),
         max_distance_m,
         mean_distance_m,
+        frechet_m,
+        hausdorff_m,
         length_m: length,
+        osm_way_ids,
+        osm_relation_ids,
+        match_tier: None,
+        other_way_ids: Vec::new(),
+        category,
+        elevation: None,
+        counterpart_elevation: None,
     });
 }
 
+/// Builders for the tests of this module and its neighbours.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    /// A section with no attributes or elevations, along one or more linestrings.
+    pub(crate) fn make_section(name: &str, geometry: impl Into<MultiLineString<f64>>) -> PctaSection {
+        PctaSection {
+            section_name: name.to_string(),
+            state: None,
+            official: None,
+            segment_id: None,
+            attributes: BTreeMap::new(),
+            geometry: geometry.into(),
+            elevations_m: Vec::new(),
+            start_m: 0.0,
+            linestring_starts_m: Vec::new(),
+        }
+    }
+
+    /// A way in relation 1, with node IDs unique to it.
+    pub(crate) fn make_way(id: u64, ls: LineString<f64>) -> OsmWay {
+        OsmWay {
+            id,
+            relation_path: vec![1],
+            node_ids: (0..ls.0.len() as u64).map(|n| id * 10_000 + n).collect(),
+            tags: Default::default(),
+            geometry: ls,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::test_support::{make_section, make_way};
     use super::*;
-    use geo::{Coord, LineString, MultiLineString};
+    use geo::{Coord, Destination, Geodesic, InterpolatePoint, LineString};
 
     /// Helper: create a straight horizontal linestring from (lon, lat) for `n` points
     /// spaced roughly `spacing_deg` apart along the x-axis at the given latitude.
@@ -265,21 +1243,261 @@ mod tests {
         LineString::from(coords)
     }
 
-    fn make_section(name: &str, ls: LineString<f64>) -> PctaSection {
-        PctaSection {
-            section_name: name.to_string(),
-            geometry: MultiLineString::new(vec![ls]),
+    /// The OSM side of the forward pass.
+    struct Osm {
+        index: RTree<IndexedSegment>,
+        paths: PathIndex,
+    }
+
+    fn index_osm(osm_ways: &[OsmWay]) -> Osm {
+        Osm {
+            index: build_index(osm_ways, None),
+            paths: PathIndex::new(assemble_paths(osm_ways)),
+        }
+    }
+
+    fn options(threshold_m: f64, min_length_m: f64, sample_interval_m: f64) -> CompareOptions {
+        CompareOptions {
+            threshold_m,
+            min_length_m,
+            sample_interval_m,
+            ..CompareOptions::default()
+        }
+    }
+
+    fn forward(sections: &[PctaSection], osm: &Osm, options: CompareOptions) -> Vec<Divergence> {
+        find_divergences(sections, &osm.index, &osm.paths, &options, None, None).divergences
+    }
+
+    fn reverse(osm_ways: &[OsmWay], sections: &[PctaSection], options: CompareOptions) -> Vec<Divergence> {
+        let pcta_index = build_pcta_index(sections);
+        let pcta_paths = PathIndex::from_sections(sections);
+        find_stale_osm_ways(osm_ways, sections, &pcta_index, &pcta_paths, &options, None).divergences
+    }
+
+    /// Reference distance from `point` to the geodesic from `a` to `b` on the WGS84 ellipsoid,
+    /// and the fraction along it of the closest point, by golden-section search.
+    fn geodesic_point_to_segment(point: Point<f64>, a: Point<f64>, b: Point<f64>) -> (f64, f64) {
+        let distance = |r: f64| Geodesic::distance(point, Geodesic::point_at_ratio_between(a, b, r));
+        let ratio = (5f64.sqrt() - 1.0) / 2.0;
+        let (mut lo, mut hi) = (0.0, 1.0);
+        for _ in 0..100 {
+            let (m1, m2) = (hi - ratio * (hi - lo), lo + ratio * (hi - lo));
+            if distance(m1) < distance(m2) {
+                hi = m2;
+            } else {
+                lo = m1;
+            }
+        }
+        let t = (lo + hi) / 2.0;
+        (distance(t), t)
+    }
+
+    #[test]
+    fn point_to_segment_matches_geodesic() {
+        // Segments of various lengths and bearings along the PCT's latitudes, with points on
+        // either side, before, alongside and beyond them
+        for lat in [32.6, 40.0, 49.0] {
+            for bearing in [0.0, 30.0, 60.0, 90.0, 135.0] {
+                for length in [50.0, 1000.0, 5000.0, 20000.0] {
+                    let a = Point::new(-120.0, lat);
+                    let b = Geodesic::destination(a, bearing, length);
+                    for along in [-0.2, 0.3, 0.9, 1.3] {
+                        for offset in [-2000.0, -50.0, 5.0, 300.0] {
+                            let foot = Geodesic::destination(a, bearing, along * length);
+                            let point = Geodesic::destination(foot, bearing + 90.0, offset);
+
+                            let distance = haversine_point_to_segment(point.0, a.0, b.0);
+                            let (_, t) = project_onto_segment(point.0, a.0, b.0);
+                            let (expected, expected_t) = geodesic_point_to_segment(point, a, b);
+
+                            // The sphere differs from the ellipsoid by up to ~0.5%
+                            let case = format!("lat {lat} bearing {bearing} length {length} at {along}, {offset}");
+                            assert!(
+                                (distance - expected).abs() <= 0.005 * expected + 0.05,
+                                "{}: {} vs {}",
+                                case,
+                                distance,
+                                expected
+                            );
+                            assert!(
+                                (t - expected_t).abs() * length <= 0.005 * (length + offset.abs()) + 0.05,
+                                "{}: t {} vs {}",
+                                case,
+                                t,
+                                expected_t
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_diagonal_segment() {
+        // A 10km segment running north-east: projecting in raw degrees puts the foot of the
+        // perpendicular over 100m off
+        let a = Point::new(-121.0, 45.0);
+        let b = Geodesic::destination(a, 45.0, 10_000.0);
+        let foot = Geodesic::destination(a, 45.0, 5_000.0);
+        let point = Geodesic::destination(foot, 135.0, 400.0);
+
+        let (closest, t) = project_onto_segment(point.0, a.0, b.0);
+
+        assert!(Geodesic::distance(Point::from(closest), foot) < 25.0);
+        assert!((t - 0.5).abs() < 0.003, "{}", t);
+        assert!((haversine_point_to_segment(point.0, a.0, b.0) - 400.0).abs() < 2.0);
+    }
+
+    /// xorshift64*, for reproducible random geometry.
+    struct Rng(u64);
+
+    impl Rng {
+        /// Uniform in `[lo, hi)`.
+        fn range(&mut self, lo: f64, hi: f64) -> f64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            let unit = (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64;
+            lo + unit * (hi - lo)
+        }
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        // Ways with segments from 10m to 20km long in every direction, at the southern and
+        // northern ends of the trail, probed from points nearby and far away. Every index lookup
+        // is checked against a search of all segments, so the index can't silently return a
+        // segment that isn't the closest.
+        let mut rng = Rng(0x5eed);
+        let options = CompareOptions {
+            search_radius_m: 2000.0,
+            ..CompareOptions::default()
+        };
+        for lat in [32.6, 48.9] {
+            let ways: Vec<OsmWay> = (0..40)
+                .map(|id| {
+                    let mut point = Point::new(-120.0 + rng.range(-0.5, 0.5), lat + rng.range(-0.5, 0.5));
+                    let coords: Vec<Coord<f64>> = (0..5)
+                        .map(|_| {
+                            let here = point;
+                            let length = 10f64.powf(rng.range(1.0, 4.3));
+                            point = Geodesic::destination(point, rng.range(0.0, 360.0), length);
+                            here.0
+                        })
+                        .collect();
+                    make_way(id, LineString::from(coords))
+                })
+                .collect();
+            let index = build_index(&ways, None);
+            let paths = PathIndex::new(ways.iter().map(|w| w.geometry.clone()).collect());
+
+            for _ in 0..2000 {
+                let coord = Coord {
+                    x: -120.0 + rng.range(-0.7, 0.7),
+                    y: lat + rng.range(-0.7, 0.7),
+                };
+                let point = index_point(coord);
+                let segments = || ways.iter().flat_map(|w| w.geometry.lines());
+                let haversine = |line: Line<f64>| haversine_point_to_segment(coord, line.start, line.end);
+
+                // In the index's own metric
+                let found = nearest(&index, coord).unwrap().distance_2(&point);
+                let best = segments().map(|line| line_distance_2(&line, &point)).fold(f64::MAX, f64::min);
+                assert!(found <= best, "{:?}: index found {} m², brute force {} m²", coord, found, best);
+
+                // And in meters
+                let (distance, _) = nearest_osm(&index, coord);
+                let brute_force = segments().map(haversine).fold(f64::MAX, f64::min);
+                assert!((distance - brute_force).abs() < 1e-6, "{:?}: {} vs {}", coord, distance, brute_force);
+
+                // Without a heading or history, the nearest candidate within the search radius
+                // wins, falling back to the nearest segment of all
+                let matched = match_segment(&index, coord, None, None, &options).unwrap();
+                let radius_2 = options.search_radius_m * options.search_radius_m;
+                let candidates: Vec<f64> = segments()
+                    .filter(|line| line_distance_2(line, &point) <= radius_2)
+                    .map(haversine)
+                    .collect();
+                let expected = candidates.into_iter().reduce(f64::min).unwrap_or(brute_force);
+                assert!(
+                    (matched.distance_m - expected).abs() < 1e-6,
+                    "{:?}: matched {} m, brute force {} m",
+                    coord,
+                    matched.distance_m,
+                    expected
+                );
+
+                // The position along the nearest path, overall and along the first path
+                for path_idx in [None, Some(0)] {
+                    let (located_idx, along_m) = paths.locate(coord, path_idx).unwrap();
+                    let (expected_idx, expected_m) = ways
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| path_idx.is_none_or(|p| p == *i))
+                        .flat_map(|(i, w)| {
+                            let mut start_m = 0.0;
+                            w.geometry.lines().map(move |line| {
+                                let (_, t) = project_onto_segment(coord, line.start, line.end);
+                                let length_m = line.length::<Haversine>();
+                                start_m += length_m;
+                                (line_distance_2(&line, &point), i, start_m - length_m + t * length_m)
+                            })
+                        })
+                        .min_by(|a, b| a.0.total_cmp(&b.0))
+                        .map(|(_, i, along_m)| (i, along_m))
+                        .unwrap();
+                    assert_eq!(located_idx, expected_idx, "{:?}", coord);
+                    assert!((along_m - expected_m).abs() < 1e-6, "{:?}: {} vs {}", coord, along_m, expected_m);
+                }
+            }
         }
     }
 
+    #[test]
+    fn match_prefers_heading_at_junctions() {
+        // The trail runs east 8m north of its OSM way; a side trail crosses it heading north
+        let main = make_way(1, horizontal_line(-118.0, 33.999928, 3, 0.001));
+        let side = make_way(2, LineString::from(vec![(-117.999, 33.999), (-117.999, 34.001)]));
+        let index = build_index(&[main, side], None);
+        let coord = Coord { x: -117.99901, y: 34.0 };
+
+        let (_, nearest_way) = nearest_osm(&index, coord);
+        assert_eq!(nearest_way.unwrap().id, 2);
+
+        let matched = match_segment(&index, coord, Some(90.0), None, &CompareOptions::default()).unwrap();
+        assert_eq!(matched.segment.way.id, 1);
+        assert!((matched.distance_m - 8.0).abs() < 0.1, "{}", matched.distance_m);
+        assert!(matched.bearing_diff_deg.unwrap() < 0.1);
+    }
+
+    #[test]
+    fn match_prefers_continuity_at_switchbacks() {
+        // Two legs of a switchback 15m apart; the sample lies 9m from the leg it was following
+        let lower = make_way(1, horizontal_line(-118.0, 34.0, 3, 0.001));
+        let upper = make_way(2, horizontal_line(-117.998, 34.000135, 3, -0.001));
+        let index = build_index(&[lower, upper], None);
+        let coord = Coord { x: -117.9995, y: 34.000081 };
+        let previous = index.iter().find(|s| s.way.id == 1 && s.segment_idx == 0).unwrap();
+
+        let options = CompareOptions::default();
+        let fresh = match_segment(&index, coord, Some(90.0), None, &options).unwrap();
+        assert_eq!(fresh.segment.way.id, 2, "Without history the nearer leg wins");
+
+        let matched = match_segment(&index, coord, Some(90.0), Some(previous), &options).unwrap();
+        assert_eq!(matched.segment.way.id, 1);
+        assert!((matched.distance_m - 9.0).abs() < 0.1, "{}", matched.distance_m);
+    }
+
     #[test]
     fn identical_lines_no_divergences() {
         let line = horizontal_line(-118.0, 34.0, 100, 0.001);
-        let osm_lines = vec![line.clone()];
-        let index = build_index(&osm_lines, None);
+        let osm_ways = vec![make_way(1, line.clone())];
+        let osm = index_osm(&osm_ways);
         let sections = vec![make_section("Test", line)];
 
-        let divs = find_divergences(&sections, &index, 10.0, 500.0, 25.0, None);
+        let divs = forward(&sections, &osm, options(10.0, 500.0, 25.0));
         assert!(divs.is_empty(), "Identical lines should produce no divergences");
     }
 
@@ -288,11 +1506,11 @@ mod tests {
         // ~50m apart at 34°N latitude: 50m / 111320m per degree ≈ 0.000449 degrees
         let pcta_line = horizontal_line(-118.0, 34.0, 200, 0.001);
         let osm_line = horizontal_line(-118.0, 34.0 + 0.000449, 200, 0.001);
-        let index = build_index(&vec![osm_line], None);
+        let osm = index_osm(&[make_way(1, osm_line)]);
         let sections = vec![make_section("Test", pcta_line)];
 
         // threshold 100m, these are ~50m apart
-        let divs = find_divergences(&sections, &index, 100.0, 500.0, 25.0, None);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
         assert!(divs.is_empty(), "Lines ~50m apart should not diverge at 100m threshold");
     }
 
@@ -301,13 +1519,15 @@ mod tests {
         // ~200m apart: 200m / 111320 ≈ 0.001797 degrees
         let pcta_line = horizontal_line(-118.0, 34.0, 500, 0.0005);
         let osm_line = horizontal_line(-118.0, 34.0 + 0.001797, 500, 0.0005);
-        let index = build_index(&vec![osm_line], None);
+        let osm = index_osm(&[make_way(1, osm_line)]);
         let sections = vec![make_section("Test", pcta_line)];
 
-        let divs = find_divergences(&sections, &index, 100.0, 500.0, 25.0, None);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
         assert!(!divs.is_empty(), "Lines ~200m apart should diverge at 100m threshold");
         assert_eq!(divs.len(), 1);
         assert!(divs[0].max_distance_m > 100.0);
+        assert_eq!(divs[0].osm_way_ids, vec![1], "Divergence should name the nearest OSM way");
+        assert_eq!(divs[0].osm_relation_ids, vec![1]);
     }
 
     #[test]
@@ -334,13 +1554,296 @@ mod tests {
 
         let pcta_line = LineString::from(pcta_coords);
         let osm_line = LineString::from(osm_coords);
-        let index = build_index(&vec![osm_line], None);
+        let osm = index_osm(&[make_way(1, osm_line)]);
         let sections = vec![make_section("Test", pcta_line)];
 
-        let divs = find_divergences(&sections, &index, 100.0, 500.0, 25.0, None);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
         assert!(!divs.is_empty(), "Should detect the middle divergence");
     }
 
+    /// A PCTA line with an OSM detour ~220m north from `detour_start` to `detour_end` (point indices),
+    /// with the OSM geometry shifted by `jitter_deg`.
+    fn detour(detour_start: usize, detour_end: usize, jitter_deg: f64) -> (Vec<PctaSection>, Osm) {
+        let pcta_line = horizontal_line(-118.0, 34.0, 300, 0.0003);
+        let osm_coords: Vec<Coord<f64>> = pcta_line
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let lat_offset = if (detour_start..detour_end).contains(&i) { 0.002 } else { 0.0 };
+                Coord {
+                    x: c.x + jitter_deg,
+                    y: c.y + lat_offset + jitter_deg,
+                }
+            })
+            .collect();
+        let osm = index_osm(&[make_way(1, LineString::from(osm_coords))]);
+        (vec![make_section("Test", pcta_line)], osm)
+    }
+
+    #[test]
+    fn divergence_id_is_stable() {
+        let (sections, osm) = detour(100, 200, 0.0);
+        let ids: Vec<String> = [25.0, 20.0, 10.0, 7.0]
+            .iter()
+            .map(|&interval| {
+                let divs = forward(&sections, &osm, options(100.0, 500.0, interval));
+                assert_eq!(divs.len(), 1);
+                divs[0].id.clone()
+            })
+            .collect();
+        assert!(ids.iter().all(|id| *id == ids[0]), "IDs should not depend on the sample interval: {:?}", ids);
+
+        // A couple of meters of geometry noise doesn't change the ID either
+        let (sections, osm) = detour(100, 200, 0.00002);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
+        assert_eq!(divs[0].id, ids[0]);
+
+        // A different stretch of trail gets a different ID
+        let (sections, osm) = detour(100, 250, 0.0);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
+        assert_ne!(divs[0].id, ids[0]);
+    }
+
+    #[test]
+    fn assembled_route_makes_mileage_continuous() {
+        // Two sections given north first, the southern one drawn north to south
+        let point = |y| Coord { x: -118.0, y };
+        let southern = LineString::from(vec![point(34.1), point(34.05), point(34.0)]);
+        let northern = LineString::from(vec![point(34.1), point(34.2)]);
+        let mut sections = vec![make_section("North", northern), make_section("South", southern)];
+
+        assert!(assemble_route(&mut sections, 5.0).is_empty());
+
+        assert_eq!(sections[0].section_name, "South");
+        assert_eq!(sections[0].geometry.0[0].0[0].y, 34.0, "Linestrings should start at their southern end");
+        assert_eq!(sections[0].start_m, 0.0);
+        let south_len = sections[0].geometry.length::<Haversine>();
+        assert!((sections[1].start_m - south_len).abs() < 1e-6);
+        assert!((south_len - 11_119.5).abs() < 10.0);
+    }
+
+    #[test]
+    fn assembled_route_keeps_elevations_with_their_vertices() {
+        // Two linestrings given north first, the southern one drawn north to south
+        let point = |y| Coord { x: -118.0, y };
+        let mut section = make_section("Test", LineString::from(vec![point(34.1), point(34.2)]));
+        section.geometry.0.push(LineString::from(vec![point(34.1), point(34.0)]));
+        section.elevations_m = vec![vec![1200.0, 1300.0], vec![1200.0, 1000.0]];
+        let mut sections = vec![section];
+
+        assert!(assemble_route(&mut sections, 5.0).is_empty());
+
+        assert_eq!(sections[0].geometry.0[0].0[0].y, 34.0);
+        assert_eq!(sections[0].elevations_m, vec![vec![1000.0, 1200.0], vec![1200.0, 1300.0]]);
+    }
+
+    #[test]
+    fn divergence_mileage() {
+        let (mut sections, osm) = detour(100, 200, 0.0);
+        sections[0].start_m = 10_000.0;
+
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
+        assert_eq!(divs.len(), 1);
+        // The detour leaves at point 100 and returns after point 199 (~27.7m apart); the PCTA
+        // only gets more than 100m from OSM within the threshold of those connecting segments
+        let spacing = 0.0003 * 111_195.0 * 34.0_f64.to_radians().cos();
+        let (leave, rejoin) = (10_000.0 + 100.0 * spacing, 10_000.0 + 199.0 * spacing);
+        assert!(divs[0].start_m > leave && divs[0].start_m < leave + 100.0, "{}", divs[0].start_m);
+        assert!(divs[0].end_m < rejoin && divs[0].end_m > rejoin - 100.0, "{}", divs[0].end_m);
+
+        // The reverse pass measures the stale way's ends at the nearest PCTA points
+        let osm_ways = vec![make_way(2, horizontal_line(-117.99, 34.002, 50, 0.0003))];
+        let stale = reverse(&osm_ways, &sections, options(100.0, 500.0, 25.0));
+        let expected_start = 10_000.0 + (0.01 / 0.0003) * spacing;
+        assert!((stale[0].start_m - expected_start).abs() < 1.0, "{}", stale[0].start_m);
+        assert!((stale[0].end_m - (expected_start + 49.0 * spacing)).abs() < 1.0);
+    }
+
+    #[test]
+    fn frechet_mode_ignores_way_splits() {
+        let line = horizontal_line(-118.0, 34.0, 100, 0.0005);
+        // The same line split into three ways sharing end nodes
+        let ways: Vec<OsmWay> = [0..40, 39..70, 69..100]
+            .into_iter()
+            .enumerate()
+            .map(|(i, range)| OsmWay {
+                node_ids: range.clone().map(|n| n as u64).collect(),
+                ..make_way(i as u64 + 1, LineString::from(line.0[range].to_vec()))
+            })
+            .collect();
+        let sections = vec![make_section("Test", line)];
+        let frechet = CompareOptions {
+            mode: CompareMode::Frechet,
+            ..options(10.0, 100.0, 25.0)
+        };
+
+        assert!(forward(&sections, &index_osm(&ways), frechet).is_empty());
+    }
+
+    #[test]
+    fn frechet_mode_sees_through_switchbacks() {
+        // The PCTA was rerouted onto a straight line; OSM still has the old alignment, which
+        // zigzags ~300m either side of it and crosses it every ~370m
+        let pcta_line = horizontal_line(-118.0, 34.0, 200, 0.0002);
+        let zigzag: Vec<Coord<f64>> = (0..=10)
+            .map(|i| Coord {
+                x: -118.0 + i as f64 * 0.00398,
+                y: match i {
+                    0 | 10 => 34.0,
+                    _ if i % 2 == 1 => 34.0027,
+                    _ => 33.9973,
+                },
+            })
+            .collect();
+        let osm = index_osm(&[make_way(1, LineString::from(zigzag))]);
+        let sections = vec![make_section("Test", pcta_line.clone())];
+        let nearest = options(50.0, 100.0, 25.0);
+
+        let divs = forward(&sections, &osm, nearest);
+        assert!(divs.len() > 1, "Crossings split the reroute in nearest mode");
+
+        let divs = forward(
+            &sections,
+            &osm,
+            CompareOptions {
+                mode: CompareMode::Frechet,
+                ..nearest
+            },
+        );
+        assert_eq!(divs.len(), 1, "{:?}", divs.iter().map(|d| d.length_m).collect::<Vec<_>>());
+        assert!(divs[0].length_m > 0.8 * pcta_line.length::<Haversine>());
+        assert!(divs[0].hausdorff_m > 250.0);
+        assert!(divs[0].frechet_m >= divs[0].hausdorff_m);
+    }
+
+    #[test]
+    fn classify_match_tiers_finds_other_paths() {
+        let (sections, osm) = detour(100, 200, 0.0);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
+        assert_eq!(divs.len(), 1);
+
+        // The PCTA follows a mapped trail that isn't in the relation
+        let pcta_line = &sections[0].geometry.0[0];
+        let trail = OsmWay {
+            relation_path: Vec::new(),
+            ..make_way(7, LineString::from(pcta_line.0[95..205].to_vec()))
+        };
+        let index = TieredIndex {
+            relation: osm.index,
+            other: Some(build_index(&[trail], None)),
+        };
+        let mut classified = divs.clone();
+        classify_match_tiers(&mut classified, &index, &options(100.0, 500.0, 25.0));
+        assert_eq!(classified[0].match_tier, Some(MatchTier::OtherPath));
+        assert_eq!(classified[0].category, DivergenceCategory::NotInRelation);
+        assert_eq!(classified[0].other_way_ids, vec![7]);
+
+        // No trail there at all
+        let index = TieredIndex {
+            other: Some(build_index(&[], None)),
+            ..index
+        };
+        let mut classified = divs;
+        classify_match_tiers(&mut classified, &index, &options(100.0, 500.0, 25.0));
+        assert_eq!(classified[0].match_tier, None);
+        assert!(classified[0].other_way_ids.is_empty());
+    }
+
+    #[test]
+    fn categories() {
+        // A 220m detour around part of the PCTA: a reroute
+        let (sections, osm) = detour(100, 200, 0.0);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
+        assert_eq!(divs[0].category, DivergenceCategory::Reroute);
+
+        // The same course throughout, 30m off: misalignment
+        let pcta_line = horizontal_line(-118.0, 34.0, 100, 0.0005);
+        let osm_line = horizontal_line(-118.0, 34.00027, 100, 0.0005);
+        let osm = index_osm(&[make_way(1, osm_line)]);
+        let sections = vec![make_section("Test", pcta_line.clone())];
+        let divs = forward(&sections, &osm, options(20.0, 500.0, 25.0));
+        assert_eq!(divs.len(), 1);
+        assert_eq!(divs[0].category, DivergenceCategory::ParallelOffset);
+
+        // The relation stops half way along the PCTA: nothing mapped
+        let osm = index_osm(&[make_way(1, LineString::from(pcta_line.0[..50].to_vec()))]);
+        let divs = forward(&sections, &osm, options(20.0, 500.0, 25.0));
+        assert_eq!(divs.len(), 1);
+        assert_eq!(divs[0].category, DivergenceCategory::MissingFromRelation);
+
+        // Only once other trails have been checked is there known to be no path at all
+        let index = TieredIndex {
+            relation: osm.index,
+            other: None,
+        };
+        let mut classified = divs.clone();
+        classify_match_tiers(&mut classified, &index, &options(20.0, 500.0, 25.0));
+        assert_eq!(classified[0].category, DivergenceCategory::MissingFromRelation);
+        let index = TieredIndex {
+            other: Some(build_index(&[], None)),
+            ..index
+        };
+        let mut classified = divs;
+        classify_match_tiers(&mut classified, &index, &options(20.0, 500.0, 25.0));
+        assert_eq!(classified[0].category, DivergenceCategory::NoOsmPath);
+    }
+
+    #[test]
+    fn offset_correction_removes_parallel_offset() {
+        // OSM 15m north of the PCTA throughout, plus a 220m detour
+        let pcta_line = horizontal_line(-118.0, 34.0, 300, 0.0003);
+        let osm_line: LineString<f64> = pcta_line
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, c)| Coord {
+                x: c.x,
+                y: c.y + if (100..150).contains(&i) { 0.002 } else { 0.000135 },
+            })
+            .collect();
+        let osm = index_osm(&[make_way(1, osm_line)]);
+        let sections = vec![make_section("Test", pcta_line.clone())];
+        let options = options(10.0, 500.0, 25.0);
+
+        let uncorrected = forward(&sections, &osm, options);
+        assert_eq!(uncorrected.len(), 1);
+        assert!(uncorrected[0].length_m > 7000.0, "The offset is reported as one long divergence");
+
+        let offsets = estimate_section_offsets(&sections, &osm.index, &options);
+        assert!((offsets[0].north_m - 15.0).abs() < 1.0, "{:?}", offsets[0]);
+        let corrected = find_divergences(&sections, &osm.index, &osm.paths, &options, Some(&offsets), None);
+        let corrected = corrected.divergences;
+        assert_eq!(corrected.len(), 1, "Only the detour remains");
+        assert!((corrected[0].length_m - 1400.0).abs() < 200.0, "{}", corrected[0].length_m);
+        // Reported on the original PCTA geometry
+        assert!(corrected[0].geometry.0.iter().all(|c| (c.y - 34.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn profile_has_every_sample() {
+        let (sections, osm) = detour(100, 200, 0.0);
+        let profiled = CompareOptions {
+            profile: true,
+            ..options(100.0, 500.0, 25.0)
+        };
+
+        let comparison = find_divergences(&sections, &osm.index, &osm.paths, &profiled, None, None);
+        let profile = &comparison.profile;
+
+        assert_eq!(profile.len(), sample_along(&sections[0].geometry.0[0], 25.0).len());
+        assert!(profile.windows(2).all(|w| w[0].trail_m < w[1].trail_m));
+        assert!(profile.iter().all(|s| s.osm_way_id == Some(1)));
+        let divergent: Vec<&ProfileSample> = profile.iter().filter(|s| s.divergent).collect();
+        let div = &comparison.divergences[0];
+        assert!(divergent.iter().all(|s| s.trail_m >= div.start_m && s.trail_m <= div.end_m));
+        assert!(divergent.iter().all(|s| s.distance_m.unwrap() > 100.0));
+        assert!((divergent.len() as f64 * 25.0 - div.length_m).abs() < 50.0);
+
+        let quiet = find_divergences(&sections, &osm.index, &osm.paths, &options(100.0, 500.0, 25.0), None, None);
+        assert!(quiet.profile.is_empty());
+    }
+
     #[test]
     fn short_divergence_filtered_out() {
         // Very short divergence (< 500m min_length)
@@ -365,10 +1868,10 @@ mod tests {
 
         let pcta_line = LineString::from(pcta_coords);
         let osm_line = LineString::from(osm_coords);
-        let index = build_index(&vec![osm_line], None);
+        let osm = index_osm(&[make_way(1, osm_line)]);
         let sections = vec![make_section("Test", pcta_line)];
 
-        let divs = find_divergences(&sections, &index, 100.0, 500.0, 25.0, None);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
         assert!(divs.is_empty(), "Short divergence should be filtered out");
     }
 
@@ -395,28 +1898,146 @@ mod tests {
 
         let pcta_line = LineString::from(pcta_coords);
         let osm_line = LineString::from(osm_coords);
-        let index = build_index(&vec![osm_line], None);
+        let osm = index_osm(&[make_way(1, osm_line)]);
         let sections = vec![make_section("Test", pcta_line)];
 
-        let divs = find_divergences(&sections, &index, 100.0, 500.0, 25.0, None);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
         assert!(divs.len() >= 2, "Should detect two separate divergences, got {}", divs.len());
     }
 
+    /// A PCTA line with an OSM detour over `detour` (point indices), whose offset in degrees of
+    /// latitude at each point is given by `offset`.
+    fn profile(detour: std::ops::Range<usize>, offset: impl Fn(usize) -> f64) -> (Vec<PctaSection>, Osm) {
+        let pcta_line = horizontal_line(-118.0, 34.0, 300, 0.0003);
+        let osm_line: LineString<f64> = pcta_line
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, c)| Coord {
+                x: c.x,
+                y: c.y + if detour.contains(&i) { offset(i) } else { 0.0 },
+            })
+            .collect();
+        (vec![make_section("Test", pcta_line)], index_osm(&[make_way(1, osm_line)]))
+    }
+
+    #[test]
+    fn exit_threshold_keeps_run_together() {
+        // ~220m off, except ~67m for 10 points in the middle
+        let (sections, osm) = profile(100..200, |i| if (140..150).contains(&i) { 0.0006 } else { 0.002 });
+
+        let split = forward(&sections, &osm, options(100.0, 500.0, 25.0));
+        assert_eq!(split.len(), 2, "The dip below the threshold splits the run");
+
+        let hysteresis = CompareOptions {
+            exit_threshold_m: Some(50.0),
+            ..options(100.0, 500.0, 25.0)
+        };
+        let divs = forward(&sections, &osm, hysteresis);
+        assert_eq!(divs.len(), 1);
+        assert!((divs[0].length_m - 2770.0).abs() < 150.0, "{}", divs[0].length_m);
+        assert!(divs[0].max_distance_m > 200.0);
+    }
+
+    #[test]
+    fn max_gap_merges_runs() {
+        // A reroute that crosses the old alignment: two 500m runs ~250m apart, each under min_length
+        let (sections, osm) = profile(100..153, |i| if (125..128).contains(&i) { 0.0 } else { 0.002 });
+
+        let divs = forward(&sections, &osm, options(100.0, 800.0, 25.0));
+        assert!(divs.is_empty(), "Each half is too short on its own");
+
+        let bridged = CompareOptions {
+            max_gap_m: 300.0,
+            ..options(100.0, 800.0, 25.0)
+        };
+        let divs = forward(&sections, &osm, bridged);
+        assert_eq!(divs.len(), 1);
+        assert!((divs[0].length_m - 1275.0).abs() < 100.0, "{}", divs[0].length_m);
+
+        let too_short = CompareOptions {
+            max_gap_m: 20.0,
+            ..options(100.0, 800.0, 25.0)
+        };
+        assert!(forward(&sections, &osm, too_short).is_empty());
+    }
+
+    #[test]
+    fn stale_osm_way_detected() {
+        // OSM has the current alignment plus an old one ~220m north that PCTA no longer follows
+        let pcta_line = horizontal_line(-118.0, 34.0, 100, 0.0003);
+        let current = make_way(1, pcta_line.clone());
+        let abandoned = make_way(2, horizontal_line(-118.0, 34.002, 100, 0.0003));
+        let osm_ways = vec![current, abandoned];
+        let sections = vec![make_section("Test", pcta_line)];
+
+        let stale = reverse(&osm_ways, &sections, options(100.0, 500.0, 25.0));
+        assert_eq!(stale.len(), 1, "Only the abandoned way should be reported");
+        assert_eq!(stale[0].kind, DivergenceKind::StaleOsmWay);
+        assert_eq!(stale[0].section_name, "Test");
+        assert_eq!(stale[0].osm_way_ids, vec![2]);
+        assert!(stale[0].geometry.0.iter().all(|c| (c.y - 34.002).abs() < 1e-9));
+
+        // The forward pass sees nothing missing
+        let osm = index_osm(&osm_ways);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
+        assert!(divs.is_empty());
+    }
+
+    #[test]
+    fn sub_metre_sample_interval() {
+        // ~185m of trail ~22m from OSM, sampled every half meter
+        let pcta_line = horizontal_line(-118.0, 34.0, 5, 0.0005);
+        let osm = index_osm(&[make_way(1, horizontal_line(-118.0, 34.0002, 5, 0.0005))]);
+        let sections = vec![make_section("Test", pcta_line)];
+        let options = options(10.0, 50.0, 0.5);
+        assert_eq!(options.curve_spacing_m(100.0), 1.0);
+
+        let divs = forward(&sections, &osm, options);
+        assert_eq!(divs.len(), 1);
+        assert!(divs[0].frechet_m > 20.0 && divs[0].frechet_m < 25.0, "{:?}", divs[0]);
+    }
+
+    #[test]
+    fn parallel_stale_ways_have_distinct_ids() {
+        // Two old alignments ~220m and ~330m north of the trail, beside the same stretch of it
+        let pcta_line = horizontal_line(-118.0, 34.0, 100, 0.0003);
+        let osm_ways = vec![
+            make_way(1, pcta_line.clone()),
+            make_way(2, horizontal_line(-118.0, 34.002, 100, 0.0003)),
+            make_way(3, horizontal_line(-118.0, 34.003, 100, 0.0003)),
+        ];
+        let sections = vec![make_section("Test", pcta_line)];
+
+        let stale = reverse(&osm_ways, &sections, options(100.0, 500.0, 25.0));
+
+        assert_eq!(stale.len(), 2);
+        // Both project onto the same stretch of trail
+        assert!((stale[0].start_m - stale[1].start_m).abs() < 1.0, "{:?}", stale);
+        assert!((stale[0].end_m - stale[1].end_m).abs() < 1.0, "{:?}", stale);
+        assert_ne!(stale[0].id, stale[1].id);
+    }
+
     #[test]
     fn empty_inputs_no_panics() {
-        let index = build_index(&[], None);
+        let osm = index_osm(&[]);
         let sections: Vec<PctaSection> = vec![];
-        let divs = find_divergences(&sections, &index, 100.0, 500.0, 25.0, None);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
         assert!(divs.is_empty());
 
         // Non-empty sections but empty index
         let line = horizontal_line(-118.0, 34.0, 10, 0.001);
         let sections = vec![make_section("Test", line)];
-        let index = build_index(&[], None);
-        let divs = find_divergences(&sections, &index, 100.0, 500.0, 25.0, None);
+        let osm = index_osm(&[]);
+        let divs = forward(&sections, &osm, options(100.0, 500.0, 25.0));
         // With empty index, every point has MAX distance, so everything is divergent
         // This should not panic
         let _ = divs;
+
+        // Reverse pass with no PCTA sections
+        let osm_ways = vec![make_way(1, horizontal_line(-118.0, 34.0, 10, 0.001))];
+        let stale = reverse(&osm_ways, &[], options(100.0, 500.0, 25.0));
+        let _ = stale;
     }
 
     #[test]
@@ -0,0 +1,303 @@
+use geo::{Coord, Densify, Euclidean, FrechetDistance, Haversine, HausdorffDistance, Length, Line, LineString};
+use rstar::{PointDistance, RTree, RTreeObject, AABB};
+use std::collections::HashMap;
+
+use super::{index_point, line_distance_2, line_envelope, project_onto_segment, PctaSection, EARTH_RADIUS_M};
+use crate::osm::OsmWay;
+
+/// An ordered path, with the distance along it to each vertex.
+struct Path {
+    line: LineString<f64>,
+    along_m: Vec<f64>,
+}
+
+/// A segment of a [`Path`] stored in the R-tree.
+struct PathSegment {
+    line: Line<f64>,
+    path_idx: usize,
+    /// Position of the segment within its path: it runs from vertex `segment_idx` to `segment_idx + 1`.
+    segment_idx: usize,
+}
+
+impl RTreeObject for PathSegment {
+    type Envelope = AABB<[f64; 3]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        line_envelope(&self.line)
+    }
+}
+
+impl PointDistance for PathSegment {
+    fn distance_2(&self, point: &[f64; 3]) -> f64 {
+        line_distance_2(&self.line, point)
+    }
+}
+
+/// Ordered paths indexed for curve matching.
+///
+/// Nearest-segment matching only looks at one point at a time; curve matching needs the
+/// stretch of the other dataset that a run corresponds to, in order, which this index finds.
+pub struct PathIndex {
+    paths: Vec<Path>,
+    tree: RTree<PathSegment>,
+}
+
+impl PathIndex {
+    pub fn new(lines: Vec<LineString<f64>>) -> Self {
+        let paths: Vec<Path> = lines
+            .into_iter()
+            .filter(|line| line.0.len() >= 2)
+            .map(|line| {
+                let along_m = std::iter::once(0.0)
+                    .chain(line.lines().scan(0.0, |along, segment| {
+                        *along += segment.length::<Haversine>();
+                        Some(*along)
+                    }))
+                    .collect();
+                Path { line, along_m }
+            })
+            .collect();
+        let segments = paths
+            .iter()
+            .enumerate()
+            .flat_map(|(path_idx, path)| {
+                path.line.lines().enumerate().map(move |(segment_idx, line)| PathSegment {
+                    line,
+                    path_idx,
+                    segment_idx,
+                })
+            })
+            .collect();
+        Self {
+            paths,
+            tree: RTree::bulk_load(segments),
+        }
+    }
+
+    /// Index the PCTA geometry, one path per section linestring.
+    pub fn from_sections(sections: &[PctaSection]) -> Self {
+        Self::new(sections.iter().flat_map(|s| s.geometry.0.iter().cloned()).collect())
+    }
+
+    /// The path nearest `coord` (restricted to `path_idx` if given) and the distance along it
+    /// to the nearest point.
+    pub(super) fn locate(&self, coord: Coord<f64>, path_idx: Option<usize>) -> Option<(usize, f64)> {
+        let segment = self
+            .tree
+            .nearest_neighbor_iter(&index_point(coord))
+            .find(|s| path_idx.is_none_or(|p| p == s.path_idx))?;
+        let path = &self.paths[segment.path_idx];
+        let (_, t) = project_onto_segment(coord, segment.line.start, segment.line.end);
+        let start = path.along_m[segment.segment_idx];
+        let end = path.along_m[segment.segment_idx + 1];
+        Some((segment.path_idx, start + t * (end - start)))
+    }
+
+    /// The stretch of the nearest path that `run` corresponds to: the path nearest the run's
+    /// midpoint, between the points nearest the run's ends, oriented the same way as `run`.
+    pub fn counterpart(&self, run: &[Coord<f64>]) -> Option<LineString<f64>> {
+        let (&first, &last) = (run.first()?, run.last()?);
+        let (path_idx, _) = self.locate(run[run.len() / 2], None)?;
+        let (_, from) = self.locate(first, Some(path_idx))?;
+        let (_, to) = self.locate(last, Some(path_idx))?;
+        Some(self.paths[path_idx].between(from, to))
+    }
+}
+
+impl Path {
+    /// The part of the path between two distances along it, reversed if `to < from`.
+    fn between(&self, from: f64, to: f64) -> LineString<f64> {
+        let (lo, hi) = (from.min(to), from.max(to));
+        let mut coords = vec![self.point_at(lo)];
+        coords.extend(
+            self.line
+                .0
+                .iter()
+                .zip(&self.along_m)
+                .filter(|&(_, &along)| along > lo && along < hi)
+                .map(|(&c, _)| c),
+        );
+        coords.push(self.point_at(hi));
+        if to < from {
+            coords.reverse();
+        }
+        LineString::from(coords)
+    }
+
+    fn point_at(&self, along: f64) -> Coord<f64> {
+        let i = self
+            .along_m
+            .partition_point(|&a| a <= along)
+            .clamp(1, self.along_m.len() - 1);
+        let (start, end) = (self.line.0[i - 1], self.line.0[i]);
+        let span = self.along_m[i] - self.along_m[i - 1];
+        let t = if span > 0.0 {
+            ((along - self.along_m[i - 1]) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        Coord {
+            x: start.x + t * (end.x - start.x),
+            y: start.y + t * (end.y - start.y),
+        }
+    }
+}
+
+/// Join OSM ways into ordered paths wherever exactly two ways meet end to end.
+///
+/// Relation members are often split at arbitrary nodes; matching a run against a single way
+/// would make every split point look like a divergence.
+pub fn assemble_paths(osm_ways: &[OsmWay]) -> Vec<LineString<f64>> {
+    let ways: Vec<&OsmWay> = osm_ways
+        .iter()
+        .filter(|w| w.node_ids.len() >= 2 && w.node_ids.len() == w.geometry.0.len())
+        .collect();
+    let mut ends: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, way) in ways.iter().enumerate() {
+        ends.entry(way.node_ids[0]).or_default().push(i);
+        ends.entry(*way.node_ids.last().unwrap()).or_default().push(i);
+    }
+    // The other way ending at `node`, if exactly two ways end there
+    let continuation = |node: u64, from: usize| match ends[&node].as_slice() {
+        &[a, b] if a != b => Some(if a == from { b } else { a }),
+        _ => None,
+    };
+
+    let mut used = vec![false; ways.len()];
+    let mut paths = Vec::new();
+    for start in 0..ways.len() {
+        if used[start] {
+            continue;
+        }
+        used[start] = true;
+        let mut coords: Vec<Coord<f64>> = ways[start].geometry.0.clone();
+        let mut nodes = (ways[start].node_ids[0], *ways[start].node_ids.last().unwrap());
+
+        // Extend forwards from the last node, then backwards from the first
+        for forwards in [true, false] {
+            let mut current = start;
+            loop {
+                let node = if forwards { nodes.1 } else { nodes.0 };
+                let Some(next) = continuation(node, current).filter(|&n| !used[n]) else {
+                    break;
+                };
+                used[next] = true;
+                let way = ways[next];
+                let mut geometry = way.geometry.0.clone();
+                // Orient the next way so it continues from `node`
+                let starts_at_node = way.node_ids[0] == node;
+                let far_node = if starts_at_node {
+                    *way.node_ids.last().unwrap()
+                } else {
+                    way.node_ids[0]
+                };
+                if forwards {
+                    if !starts_at_node {
+                        geometry.reverse();
+                    }
+                    coords.extend_from_slice(&geometry[1..]);
+                    nodes.1 = far_node;
+                } else {
+                    if starts_at_node {
+                        geometry.reverse();
+                    }
+                    geometry.pop();
+                    geometry.append(&mut coords);
+                    coords = geometry;
+                    nodes.0 = far_node;
+                }
+                current = next;
+            }
+        }
+        paths.push(LineString::from(coords));
+    }
+    paths
+}
+
+/// Discrete Fréchet and Hausdorff distances in meters between `a` and `b`.
+///
+/// Both lines are projected to local meters around `a`'s first point and densified to
+/// `spacing_m`, since the discrete measures only look at vertices.
+pub fn curve_distances(a: &LineString<f64>, b: &LineString<f64>, spacing_m: f64) -> (f64, f64) {
+    let Some(&origin) = a.0.first() else {
+        return (f64::MAX, f64::MAX);
+    };
+    let a = to_local(a, origin).densify::<Euclidean>(spacing_m);
+    let b = to_local(b, origin).densify::<Euclidean>(spacing_m);
+    (a.frechet_distance(&b), a.hausdorff_distance(&b))
+}
+
+/// Equirectangular projection to meters east and north of `origin`. Accurate to well under a
+/// meter over the few kilometers a run spans.
+fn to_local(ls: &LineString<f64>, origin: Coord<f64>) -> LineString<f64> {
+    let scale_x = origin.y.to_radians().cos() * EARTH_RADIUS_M;
+    ls.0.iter()
+        .map(|c| Coord {
+            x: (c.x - origin.x).to_radians() * scale_x,
+            y: (c.y - origin.y).to_radians() * EARTH_RADIUS_M,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::test_support::make_way;
+
+    fn way(id: u64, node_ids: Vec<u64>, coords: Vec<(f64, f64)>) -> OsmWay {
+        OsmWay {
+            node_ids,
+            ..make_way(id, LineString::from(coords))
+        }
+    }
+
+    #[test]
+    fn assemble_paths_joins_split_ways() {
+        // Three ways forming one path, the middle one drawn backwards, plus a spur at node 3
+        let ways = vec![
+            way(1, vec![1, 2], vec![(0.0, 0.0), (1.0, 0.0)]),
+            way(2, vec![3, 2], vec![(2.0, 0.0), (1.0, 0.0)]),
+            way(3, vec![3, 4], vec![(2.0, 0.0), (3.0, 0.0)]),
+            way(4, vec![3, 5], vec![(2.0, 0.0), (2.0, 1.0)]),
+        ];
+
+        let paths = assemble_paths(&ways);
+
+        assert_eq!(paths.len(), 3, "The spur's junction node splits the path");
+        assert_eq!(paths[0], LineString::from(vec![(0.0, 0.0), (1.0, 0.0), (2.0, 0.0)]));
+
+        let chain = vec![ways[0].clone(), ways[1].clone(), ways[2].clone()];
+        let paths = assemble_paths(&chain);
+        assert_eq!(paths, vec![LineString::from(vec![(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0)])]);
+    }
+
+    #[test]
+    fn counterpart_follows_run_direction() {
+        let index = PathIndex::new(vec![LineString::from(vec![(-118.0, 34.0), (-117.99, 34.0), (-117.98, 34.0)])]);
+        let run = [Coord { x: -117.985, y: 34.001 }, Coord { x: -117.995, y: 34.001 }];
+
+        let counterpart = index.counterpart(&run).unwrap();
+
+        let expected = LineString::from(vec![(-117.985, 34.0), (-117.99, 34.0), (-117.995, 34.0)]);
+        for (c, e) in counterpart.0.iter().zip(&expected.0) {
+            assert!((c.x - e.x).abs() < 1e-9 && (c.y - e.y).abs() < 1e-9, "{:?}", counterpart);
+        }
+        assert_eq!(counterpart.0.len(), 3);
+    }
+
+    #[test]
+    fn curve_distances_in_meters() {
+        // ~111m apart north-south
+        let a = LineString::from(vec![(-118.0, 34.0), (-117.99, 34.0)]);
+        let b = LineString::from(vec![(-118.0, 34.001), (-117.99, 34.001)]);
+        let (frechet, hausdorff) = curve_distances(&a, &b, 5.0);
+        assert!((frechet - 111.2).abs() < 0.5, "{}", frechet);
+        assert!((hausdorff - 111.2).abs() < 0.5, "{}", hausdorff);
+
+        // Same line traversed backwards: close as a point set, far apart as a curve
+        let reversed = LineString::from(vec![(-117.99, 34.0), (-118.0, 34.0)]);
+        let (frechet, hausdorff) = curve_distances(&a, &reversed, 5.0);
+        assert!(frechet > 900.0);
+        assert!(hausdorff < 5.0);
+    }
+}
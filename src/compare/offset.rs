@@ -0,0 +1,245 @@
+use geo::{Coord, LineString, MultiLineString};
+use rayon::prelude::*;
+use rstar::RTree;
+
+use super::{
+    haversine_point_to_segment, index_point, project_onto_segment, sample_along_with_positions, CompareOptions,
+    IndexedSegment, PctaSection, EARTH_RADIUS_M,
+};
+
+/// Samples further than this from OSM are left out of the fit: they're reroutes, not offsets.
+const OFFSET_MATCH_RADIUS_M: f64 = 50.0;
+/// Fraction of a section's samples that must be matched for its offset to be trusted.
+const OFFSET_MIN_MATCHED_FRACTION: f64 = 0.5;
+/// Each iteration only fits the samples within this many times the median distance, so the
+/// ends of reroutes, where OSM veers off, don't bias the fit.
+const OFFSET_OUTLIER_FACTOR: f64 = 3.0;
+/// Lower bound (meters) on the outlier cutoff, so GPS noise is never trimmed away.
+const OFFSET_MIN_CUTOFF_M: f64 = 5.0;
+const OFFSET_MAX_ITERATIONS: usize = 20;
+/// Stop iterating once an iteration moves the offset by less than this (meters).
+const OFFSET_CONVERGENCE_M: f64 = 0.01;
+
+/// The best-fit translation of a PCTA section onto the OSM geometry.
+///
+/// Long stretches sitting a steady distance off are usually imagery or GPS offsets rather than
+/// reroutes; the translation measures that offset so it can be reported or corrected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectionOffset {
+    pub section_name: String,
+    /// Meters east that the PCTA must move to line up with OSM.
+    pub east_m: f64,
+    /// Meters north that the PCTA must move to line up with OSM.
+    pub north_m: f64,
+    /// RMS distance (meters) from the matched samples to OSM, before the translation.
+    pub rms_before_m: f64,
+    /// RMS distance (meters) from the matched samples to OSM, after the translation.
+    pub rms_after_m: f64,
+    /// Samples within [`OFFSET_MATCH_RADIUS_M`] of OSM, which the fit is based on.
+    pub matched: usize,
+    pub samples: usize,
+}
+
+impl SectionOffset {
+    pub fn magnitude_m(&self) -> f64 {
+        self.east_m.hypot(self.north_m)
+    }
+
+    /// Whether enough of the section lies near OSM for the offset to mean anything.
+    pub fn is_reliable(&self) -> bool {
+        self.samples > 0 && self.matched as f64 >= OFFSET_MIN_MATCHED_FRACTION * self.samples as f64
+    }
+
+    /// Move `coord` by the offset.
+    pub fn apply(&self, coord: Coord<f64>) -> Coord<f64> {
+        let y = coord.y + (self.north_m / EARTH_RADIUS_M).to_degrees();
+        Coord {
+            x: coord.x + self.east_degrees(y),
+            y,
+        }
+    }
+
+    /// Undo [`SectionOffset::apply`].
+    pub fn remove(&self, coord: Coord<f64>) -> Coord<f64> {
+        Coord {
+            x: coord.x - self.east_degrees(coord.y),
+            y: coord.y - (self.north_m / EARTH_RADIUS_M).to_degrees(),
+        }
+    }
+
+    /// A copy of `section` moved by the offset.
+    pub fn apply_to_section(&self, section: &PctaSection) -> PctaSection {
+        PctaSection {
+            geometry: MultiLineString::new(
+                section
+                    .geometry
+                    .0
+                    .iter()
+                    .map(|ls| ls.0.iter().map(|&c| self.apply(c)).collect::<LineString<f64>>())
+                    .collect(),
+            ),
+            ..section.clone()
+        }
+    }
+
+    /// `east_m` in degrees of longitude at latitude `y` (of the moved coordinate, so
+    /// `apply` and `remove` are exact inverses).
+    fn east_degrees(&self, y: f64) -> f64 {
+        (self.east_m / (EARTH_RADIUS_M * y.to_radians().cos())).to_degrees()
+    }
+}
+
+/// Estimate the offset of each section from the OSM geometry, in the same order as `sections`.
+///
+/// The fit is a translation-only iterative closest point: samples along the section are paired
+/// with the nearest point on OSM, and the offset is moved by the mean of the pairs' differences
+/// until it settles. Samples further than [`OFFSET_MATCH_RADIUS_M`] from OSM, and outliers
+/// among the rest, are ignored, so real reroutes don't drag the fit.
+pub fn estimate_section_offsets(
+    sections: &[PctaSection],
+    osm_index: &RTree<IndexedSegment>,
+    options: &CompareOptions,
+) -> Vec<SectionOffset> {
+    sections
+        .par_iter()
+        .map(|section| estimate_offset(section, osm_index, options.sample_interval_m))
+        .collect()
+}
+
+fn estimate_offset(section: &PctaSection, osm_index: &RTree<IndexedSegment>, interval_m: f64) -> SectionOffset {
+    let samples: Vec<Coord<f64>> = section
+        .geometry
+        .0
+        .iter()
+        .flat_map(|ls| sample_along_with_positions(ls, interval_m))
+        .map(|(coord, _)| coord)
+        .collect();
+    let mut offset = SectionOffset {
+        section_name: section.section_name.clone(),
+        east_m: 0.0,
+        north_m: 0.0,
+        rms_before_m: 0.0,
+        rms_after_m: 0.0,
+        matched: 0,
+        samples: samples.len(),
+    };
+
+    // Meters east and north from each moved sample to the nearest point on OSM, if close enough
+    let residuals = |offset: &SectionOffset| -> Vec<(f64, f64)> {
+        samples
+            .iter()
+            .filter_map(|&coord| {
+                let moved = offset.apply(coord);
+                let segment = nearest_within(osm_index, moved, OFFSET_MATCH_RADIUS_M)?;
+                let (nearest, _) = project_onto_segment(moved, segment.line.start, segment.line.end);
+                Some(local_delta(moved, nearest))
+            })
+            .collect()
+    };
+    let rms = |r: &[(f64, f64)]| (r.iter().map(|(e, n)| e * e + n * n).sum::<f64>() / r.len().max(1) as f64).sqrt();
+
+    let initial = residuals(&offset);
+    let mut current = initial.clone();
+    for _ in 0..OFFSET_MAX_ITERATIONS {
+        if current.is_empty() {
+            break;
+        }
+        let mut magnitudes: Vec<f64> = current.iter().map(|(e, n)| e.hypot(*n)).collect();
+        magnitudes.sort_by(f64::total_cmp);
+        let cutoff = (OFFSET_OUTLIER_FACTOR * magnitudes[magnitudes.len() / 2]).max(OFFSET_MIN_CUTOFF_M);
+        let inliers: Vec<&(f64, f64)> = current.iter().filter(|(e, n)| e.hypot(*n) <= cutoff).collect();
+
+        let n = inliers.len() as f64;
+        let (east, north) = inliers.iter().fold((0.0, 0.0), |(e, n), (de, dn)| (e + de, n + dn));
+        offset.east_m += east / n;
+        offset.north_m += north / n;
+        current = residuals(&offset);
+        if (east / n).hypot(north / n) < OFFSET_CONVERGENCE_M {
+            break;
+        }
+    }
+
+    offset.rms_before_m = rms(&initial);
+    offset.rms_after_m = rms(&current);
+    offset.matched = initial.len();
+    offset
+}
+
+/// The segment nearest `coord` within `radius_m`.
+fn nearest_within(osm_index: &RTree<IndexedSegment>, coord: Coord<f64>, radius_m: f64) -> Option<&IndexedSegment> {
+    // Chords are never longer than arcs, so this finds every segment within the radius
+    osm_index
+        .locate_within_distance(index_point(coord), radius_m * radius_m)
+        .map(|segment| (segment, haversine_point_to_segment(coord, segment.line.start, segment.line.end)))
+        .filter(|&(_, distance)| distance <= radius_m)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(segment, _)| segment)
+}
+
+/// Meters east and north from `from` to `to`.
+fn local_delta(from: Coord<f64>, to: Coord<f64>) -> (f64, f64) {
+    (
+        (to.x - from.x).to_radians() * from.y.to_radians().cos() * EARTH_RADIUS_M,
+        (to.y - from.y).to_radians() * EARTH_RADIUS_M,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::build_index;
+    use crate::compare::test_support::{make_section, make_way};
+
+    #[test]
+    fn estimates_translation() {
+        // An L-shaped trail, so both components of the offset are observable
+        let pcta = make_section("Test", vec![(-118.0, 34.0), (-117.99, 34.0), (-117.99, 34.01)]);
+        let truth = SectionOffset {
+            east_m: 15.0,
+            north_m: -10.0,
+            ..estimate_offset(&pcta, &RTree::new(), 25.0)
+        };
+        let osm = truth.apply_to_section(&pcta);
+        let index = build_index(&[make_way(1, osm.geometry.0[0].clone())], None);
+
+        let offset = estimate_offset(&pcta, &index, 25.0);
+
+        assert!((offset.east_m - 15.0).abs() < 0.5, "{:?}", offset);
+        assert!((offset.north_m + 10.0).abs() < 0.5, "{:?}", offset);
+        assert!(offset.is_reliable());
+        assert!(offset.rms_before_m > 10.0 && offset.rms_after_m < 0.5, "{:?}", offset);
+    }
+
+    #[test]
+    fn ignores_reroutes() {
+        // OSM follows the PCTA 12m north, except for a stretch where it's 300m away
+        let pcta_line: LineString<f64> = (0..=40).map(|i| (-118.0 + i as f64 * 0.001, 34.0)).collect();
+        let pcta = make_section("Test", pcta_line);
+        let osm: LineString<f64> = (0..=40)
+            .map(|i| {
+                let lat = if (15..25).contains(&i) { 34.0027 } else { 34.000108 };
+                (-118.0 + i as f64 * 0.001, lat)
+            })
+            .collect();
+        let index = build_index(&[make_way(1, osm)], None);
+
+        let offset = estimate_offset(&pcta, &index, 25.0);
+
+        assert!((offset.north_m - 12.0).abs() < 0.5, "{:?}", offset);
+        // Along a straight trail only the ends of the reroute constrain the east offset
+        assert!(offset.east_m.abs() < 1.0, "{:?}", offset);
+        assert!(offset.matched < offset.samples);
+    }
+
+    #[test]
+    fn apply_and_remove_are_inverse() {
+        let offset = SectionOffset {
+            east_m: 20.0,
+            north_m: 5.0,
+            ..estimate_offset(&make_section("Test", vec![(0.0, 0.0), (0.0, 0.001)]), &RTree::new(), 25.0)
+        };
+        let coord = Coord { x: -121.5, y: 45.7 };
+        let back = offset.remove(offset.apply(coord));
+        assert!((back.x - coord.x).abs() < 1e-12 && (back.y - coord.y).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,279 @@
+use geo::{Coord, Distance, Haversine, Length, LineString, Point};
+
+use super::{haversine_point_to_segment, PctaSection};
+
+/// A break in the PCTA centerline where one linestring doesn't pick up where the last one ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteFinding {
+    pub kind: RouteFindingKind,
+    /// Section of the linestring before the break.
+    pub from_section: String,
+    /// Section of the linestring after the break; the same as `from_section` within a section.
+    pub to_section: String,
+    /// For a gap, the straight line across it; for an overlap, the stretch drawn twice.
+    pub geometry: LineString<f64>,
+    /// Length of the gap or overlap, in meters.
+    pub length_m: f64,
+    /// Distance along the assembled route to the break, in meters.
+    pub trail_m: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteFindingKind {
+    /// The next linestring starts further than the snap tolerance from the end of the last.
+    Gap,
+    /// The next linestring starts by retracing the end of the last.
+    Overlap,
+}
+
+impl RouteFindingKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteFindingKind::Gap => "gap",
+            RouteFindingKind::Overlap => "overlap",
+        }
+    }
+}
+
+/// A linestring of a section, with its elevations if the section has any.
+type Part = (LineString<f64>, Option<Vec<f64>>);
+
+/// Stitch the sections' linestrings into one route from Mexico to Canada, and set each section's
+/// [`PctaSection::start_m`] and [`PctaSection::linestring_starts_m`] so mileage is continuous
+/// along it.
+///
+/// The route starts at the southernmost endpoint of any linestring and repeatedly continues with
+/// the linestring whose nearer end is closest to where it left off, reversed if need be, so the
+/// order and direction of the source don't matter. Ends within `snap_tolerance_m` of each other
+/// are snapped together; anything further is reported as a gap. A linestring that starts by
+/// retracing the previous one is trimmed to where it leaves it, and the retraced stretch is
+/// reported as an overlap, so it isn't counted twice. Sections are then ordered by where the
+/// route first enters them, with their linestrings in route order.
+///
+/// Mileage counts only the linestrings, not the gaps between them. A section's linestrings needn't
+/// be consecutive along the route; each gets its own start.
+pub fn assemble_route(sections: &mut Vec<PctaSection>, snap_tolerance_m: f64) -> Vec<RouteFinding> {
+    let mut parts: Vec<Vec<Option<Part>>> = sections
+        .iter_mut()
+        .map(|section| {
+            let mut elevations = std::mem::take(&mut section.elevations_m).into_iter();
+            std::mem::take(&mut section.geometry.0)
+                .into_iter()
+                .map(|ls| Some((ls, elevations.next())))
+                .collect()
+        })
+        .collect();
+    let mut remaining: Vec<(usize, usize)> = parts
+        .iter()
+        .enumerate()
+        .flat_map(|(s, lines)| (0..lines.len()).map(move |p| (s, p)))
+        .filter(|&(s, p)| parts[s][p].as_ref().is_some_and(|(ls, _)| !ls.0.is_empty()))
+        .collect();
+
+    let mut chain: Vec<(usize, usize)> = Vec::with_capacity(remaining.len());
+    // Distance along the route to the start of each part of the chain
+    let mut starts_m: Vec<f64> = Vec::with_capacity(remaining.len());
+    let mut findings = Vec::new();
+    let mut trail_m = 0.0;
+
+    while !remaining.is_empty() {
+        let ends = |&(s, p): &(usize, usize)| {
+            let ls = &parts[s][p].as_ref().unwrap().0;
+            (ls.0[0], ls.0[ls.0.len() - 1])
+        };
+        // The next part, whether it needs reversing, and how far its start is from the route's end
+        let (idx, reverse, distance_m) = match chain.last() {
+            None => remaining
+                .iter()
+                .enumerate()
+                .flat_map(|(i, part)| {
+                    let (first, last) = ends(part);
+                    [(i, false, first.y), (i, true, last.y)]
+                })
+                .min_by(|a, b| a.2.total_cmp(&b.2))
+                .map(|(i, reverse, _)| (i, reverse, 0.0))
+                .unwrap(),
+            Some(&previous) => {
+                let end = ends(&previous).1;
+                remaining
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, part)| {
+                        let (first, last) = ends(part);
+                        [(i, false, haversine(end, first)), (i, true, haversine(end, last))]
+                    })
+                    .min_by(|a, b| a.2.total_cmp(&b.2))
+                    .unwrap()
+            }
+        };
+        let next = remaining.swap_remove(idx);
+        let (ls, z) = parts[next.0][next.1].as_mut().unwrap();
+        if reverse {
+            ls.0.reverse();
+            if let Some(z) = z {
+                z.reverse();
+            }
+        }
+
+        if let Some(&previous) = chain.last() {
+            let previous_line = parts[previous.0][previous.1].as_ref().unwrap().0.clone();
+            let end = previous_line.0[previous_line.0.len() - 1];
+            let (ls, z) = parts[next.0][next.1].as_mut().unwrap();
+            let break_between = |kind, geometry: LineString<f64>| RouteFinding {
+                kind,
+                from_section: sections[previous.0].section_name.clone(),
+                to_section: sections[next.0].section_name.clone(),
+                length_m: geometry.length::<Haversine>(),
+                geometry,
+                trail_m,
+            };
+            if distance_m > snap_tolerance_m {
+                findings.push(break_between(RouteFindingKind::Gap, LineString::new(vec![end, ls.0[0]])));
+            } else {
+                ls.0[0] = end;
+                let retraced = retraced_vertices(&previous_line, ls, snap_tolerance_m);
+                let overlap = LineString::new(ls.0[..retraced].to_vec());
+                if overlap.length::<Haversine>() > snap_tolerance_m {
+                    findings.push(break_between(RouteFindingKind::Overlap, overlap));
+                    // Keep at least a segment, even of a linestring that's retraced throughout
+                    let trim = (retraced - 1).min(ls.0.len() - 2);
+                    ls.0.drain(..trim);
+                    if let Some(z) = z {
+                        z.drain(..trim.min(z.len()));
+                    }
+                }
+            }
+        }
+        starts_m.push(trail_m);
+        trail_m += parts[next.0][next.1].as_ref().unwrap().0.length::<Haversine>();
+        chain.push(next);
+    }
+
+    // Sections in the order the route reaches them, then any without geometry
+    let mut order: Vec<usize> = Vec::with_capacity(sections.len());
+    for section in sections.iter_mut() {
+        section.start_m = trail_m;
+        section.linestring_starts_m.clear();
+    }
+    for (&(s, p), &start_m) in chain.iter().zip(&starts_m) {
+        if !order.contains(&s) {
+            order.push(s);
+            sections[s].start_m = start_m;
+        }
+        let (ls, z) = parts[s][p].take().unwrap();
+        sections[s].geometry.0.push(ls);
+        sections[s].elevations_m.extend(z);
+        sections[s].linestring_starts_m.push(start_m);
+    }
+    order.extend((0..sections.len()).filter(|s| !chain.iter().any(|&(c, _)| c == *s)));
+    let mut unordered: Vec<Option<PctaSection>> = std::mem::take(sections).into_iter().map(Some).collect();
+    *sections = order.into_iter().filter_map(|s| unordered[s].take()).collect();
+    findings
+}
+
+fn haversine(a: Coord<f64>, b: Coord<f64>) -> f64 {
+    Haversine::distance(Point::from(a), Point::from(b))
+}
+
+/// How many of `next`'s leading vertices lie within `tolerance_m` of `previous`.
+fn retraced_vertices(previous: &LineString<f64>, next: &LineString<f64>, tolerance_m: f64) -> usize {
+    next.0
+        .iter()
+        .take_while(|&&coord| {
+            previous
+                .lines()
+                .any(|line| haversine_point_to_segment(coord, line.start, line.end) <= tolerance_m)
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::test_support::make_section;
+    use geo::MultiLineString;
+
+    fn names(sections: &[PctaSection]) -> Vec<&str> {
+        sections.iter().map(|s| s.section_name.as_str()).collect()
+    }
+
+    #[test]
+    fn chains_parts_end_to_end() {
+        // A trail heading east then north, given out of order and partly reversed, with a 2m
+        // break between A and B
+        let mut sections = vec![
+            make_section(
+                "B",
+                MultiLineString::from_iter([
+                    vec![(-117.98, 34.01), (-117.98, 34.02)],
+                    vec![(-117.98, 34.00002), (-117.98, 34.01)],
+                ]),
+            ),
+            make_section("A", vec![(-117.98, 34.0), (-117.99, 34.0), (-118.0, 33.9999)]),
+        ];
+
+        let findings = assemble_route(&mut sections, 5.0);
+
+        assert!(findings.is_empty(), "{:?}", findings);
+        assert_eq!(names(&sections), vec!["A", "B"]);
+        let a = &sections[0].geometry.0[0];
+        assert_eq!(a.0[2], Coord { x: -117.98, y: 34.0 }, "A should end where B starts: {:?}", a);
+        let b = &sections[1].geometry.0;
+        assert_eq!(b[0].0[0], a.0[2], "B's first part should be snapped onto A's end");
+        assert_eq!(b[1].0, vec![Coord { x: -117.98, y: 34.01 }, Coord { x: -117.98, y: 34.02 }]);
+        let a_len = sections[0].geometry.length::<Haversine>();
+        assert!((sections[1].start_m - a_len).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sections_can_interleave() {
+        // A's two linestrings on either side of B
+        let mut sections = vec![
+            make_section(
+                "A",
+                MultiLineString::from_iter([
+                    vec![(-118.0, 34.02), (-118.0, 34.03)],
+                    vec![(-118.0, 34.0), (-118.0, 34.01)],
+                ]),
+            ),
+            make_section("B", vec![(-118.0, 34.01), (-118.0, 34.02)]),
+        ];
+
+        assert!(assemble_route(&mut sections, 5.0).is_empty());
+
+        assert_eq!(names(&sections), vec!["A", "B"]);
+        let leg_m = sections[1].geometry.length::<Haversine>();
+        assert!((sections[1].start_m - leg_m).abs() < 1e-6);
+        let starts: Vec<f64> = sections[0].linestrings_with_offsets().map(|(_, start)| start).collect();
+        assert_eq!(starts[0], 0.0);
+        assert!((starts[1] - 2.0 * leg_m).abs() < 1e-6, "A resumes after B: {:?}", starts);
+    }
+
+    #[test]
+    fn reports_gaps_and_overlaps() {
+        let mut sections = vec![
+            make_section("A", vec![(-118.0, 34.0), (-118.0, 34.01)]),
+            // Starts 111m north of A's end
+            make_section("B", vec![(-118.0, 34.011), (-118.0, 34.02)]),
+            // Retraces the last ~550m of B before heading east
+            make_section("C", vec![(-118.0, 34.02), (-118.0, 34.015), (-118.0, 34.01505), (-117.99, 34.015)]),
+        ];
+
+        let findings = assemble_route(&mut sections, 5.0);
+
+        assert_eq!(names(&sections), vec!["A", "B", "C"]);
+        assert_eq!(findings.len(), 2, "{:?}", findings);
+        let gap = &findings[0];
+        assert_eq!(gap.kind, RouteFindingKind::Gap);
+        assert_eq!((gap.from_section.as_str(), gap.to_section.as_str()), ("A", "B"));
+        assert!((gap.length_m - 111.2).abs() < 0.5, "{:?}", gap);
+        assert!((gap.trail_m - 1111.9).abs() < 1.0, "{:?}", gap);
+
+        let overlap = &findings[1];
+        assert_eq!(overlap.kind, RouteFindingKind::Overlap);
+        assert_eq!((overlap.from_section.as_str(), overlap.to_section.as_str()), ("B", "C"));
+        assert!((overlap.length_m - 561.5).abs() < 1.0, "{:?}", overlap);
+        // C now starts where it leaves B
+        assert_eq!(sections[2].geometry.0[0].0[0], Coord { x: -118.0, y: 34.01505 });
+    }
+}
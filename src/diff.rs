@@ -0,0 +1,276 @@
+use geo::{BoundingRect, Coord, Haversine, LineString, Point, Rect};
+use geo::Distance;
+use std::cmp::Ordering;
+
+use crate::compare::{haversine_point_to_segment, sample_along, Divergence};
+
+/// Spacing of the points used to measure how much two divergences overlap.
+const OVERLAP_SAMPLE_INTERVAL_M: f64 = 10.0;
+
+/// How a divergence changed between two runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    /// Only in the new run.
+    New,
+    /// Only in the old run: fixed in OSM, or the PCTA data changed.
+    Resolved,
+    Grown,
+    Shrunk,
+    Unchanged,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::New => "new",
+            ChangeKind::Resolved => "resolved",
+            ChangeKind::Grown => "grown",
+            ChangeKind::Shrunk => "shrunk",
+            ChangeKind::Unchanged => "unchanged",
+        }
+    }
+}
+
+/// A divergence matched across two runs. `old` is `None` for `New`, `new` is `None` for `Resolved`.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub old: Option<Divergence>,
+    pub new: Option<Divergence>,
+    /// Length of the new divergence lying within the tolerance of the old one.
+    pub overlap_m: f64,
+}
+
+impl Change {
+    /// The current divergence, or the old one if it was resolved.
+    pub fn divergence(&self) -> &Divergence {
+        self.new.as_ref().or(self.old.as_ref()).expect("a change has at least one side")
+    }
+
+    /// Length difference in meters (new minus old; missing sides count as zero).
+    pub fn length_change_m(&self) -> f64 {
+        self.new.as_ref().map_or(0.0, |d| d.length_m) - self.old.as_ref().map_or(0.0, |d| d.length_m)
+    }
+}
+
+/// Match divergences between two runs by spatial overlap and classify each one.
+///
+/// Two divergences of the same kind match when part of the new one lies within `tolerance_m`
+/// of the old one; each divergence is matched at most once, largest overlap first. Matched
+/// pairs whose length changed by more than `min_change_m` are `Grown` or `Shrunk`.
+pub fn diff_divergences(old: &[Divergence], new: &[Divergence], tolerance_m: f64, min_change_m: f64) -> Vec<Change> {
+    let old_bounds: Vec<Option<Rect<f64>>> = old.iter().map(|d| expanded_bounds(&d.geometry, tolerance_m)).collect();
+
+    let mut candidates: Vec<(usize, usize, f64)> = Vec::new();
+    for (j, n) in new.iter().enumerate() {
+        let Some(new_rect) = n.geometry.bounding_rect() else {
+            continue;
+        };
+        for (i, o) in old.iter().enumerate() {
+            if o.kind != n.kind || !old_bounds[i].is_some_and(|r| intersects(&r, &new_rect)) {
+                continue;
+            }
+            let overlap = overlap_length(&n.geometry, &o.geometry, tolerance_m);
+            if overlap > 0.0 {
+                candidates.push((i, j, overlap));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal));
+
+    let mut old_matched = vec![false; old.len()];
+    let mut new_matched = vec![false; new.len()];
+    let mut changes = Vec::new();
+    for (i, j, overlap_m) in candidates {
+        if old_matched[i] || new_matched[j] {
+            continue;
+        }
+        old_matched[i] = true;
+        new_matched[j] = true;
+
+        let delta = new[j].length_m - old[i].length_m;
+        let kind = if delta > min_change_m {
+            ChangeKind::Grown
+        } else if delta < -min_change_m {
+            ChangeKind::Shrunk
+        } else {
+            ChangeKind::Unchanged
+        };
+        changes.push(Change {
+            kind,
+            old: Some(old[i].clone()),
+            new: Some(new[j].clone()),
+            overlap_m,
+        });
+    }
+
+    for (n, _) in new.iter().zip(&new_matched).filter(|(_, matched)| !**matched) {
+        changes.push(Change {
+            kind: ChangeKind::New,
+            old: None,
+            new: Some(n.clone()),
+            overlap_m: 0.0,
+        });
+    }
+    for (o, _) in old.iter().zip(&old_matched).filter(|(_, matched)| !**matched) {
+        changes.push(Change {
+            kind: ChangeKind::Resolved,
+            old: Some(o.clone()),
+            new: None,
+            overlap_m: 0.0,
+        });
+    }
+
+    changes.sort_by(|a, b| {
+        a.kind
+            .cmp(&b.kind)
+            .then_with(|| a.divergence().section_name.cmp(&b.divergence().section_name))
+            .then_with(|| b.divergence().length_m.partial_cmp(&a.divergence().length_m).unwrap_or(Ordering::Equal))
+    });
+    changes
+}
+
+/// Length of `a` lying within `tolerance_m` of `b`.
+fn overlap_length(a: &LineString<f64>, b: &LineString<f64>, tolerance_m: f64) -> f64 {
+    let samples = sample_along(a, OVERLAP_SAMPLE_INTERVAL_M);
+    let near: Vec<bool> = samples
+        .iter()
+        .map(|&coord| distance_to_linestring(coord, b) <= tolerance_m)
+        .collect();
+
+    samples
+        .windows(2)
+        .zip(near.windows(2))
+        .filter(|(_, near)| near[0] && near[1])
+        .map(|(pair, _)| Haversine::distance(Point::from(pair[0]), Point::from(pair[1])))
+        .sum()
+}
+
+fn distance_to_linestring(coord: Coord<f64>, ls: &LineString<f64>) -> f64 {
+    match ls.0.as_slice() {
+        [] => f64::MAX,
+        [only] => Haversine::distance(Point::from(coord), Point::from(*only)),
+        _ => ls
+            .lines()
+            .map(|line| haversine_point_to_segment(coord, line.start, line.end))
+            .fold(f64::MAX, f64::min),
+    }
+}
+
+/// Bounding box of `ls` grown by `margin_m` on every side.
+fn expanded_bounds(ls: &LineString<f64>, margin_m: f64) -> Option<Rect<f64>> {
+    let rect = ls.bounding_rect()?;
+    let max_lat = rect.min().y.abs().max(rect.max().y.abs()).min(89.0);
+    let dy = margin_m / 111_320.0;
+    let dx = dy / max_lat.to_radians().cos();
+    Some(Rect::new(
+        Coord {
+            x: rect.min().x - dx,
+            y: rect.min().y - dy,
+        },
+        Coord {
+            x: rect.max().x + dx,
+            y: rect.max().y + dy,
+        },
+    ))
+}
+
+fn intersects(a: &Rect<f64>, b: &Rect<f64>) -> bool {
+    a.min().x <= b.max().x && b.min().x <= a.max().x && a.min().y <= b.max().y && b.min().y <= a.max().y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::{DivergenceCategory, DivergenceKind};
+    use geo::Length;
+
+    fn divergence(kind: DivergenceKind, start_lon: f64, end_lon: f64) -> Divergence {
+        let geometry = LineString::from(vec![(start_lon, 34.0), (end_lon, 34.0)]);
+        Divergence {
+            id: String::new(),
+            kind,
+            length_m: geometry.length::<Haversine>(),
+            geometry,
+            section_name: "CA Section A".to_string(),
+            state: None,
+            official: None,
+            segment_id: None,
+            pcta_attributes: Default::default(),
+            start_m: 0.0,
+            end_m: 0.0,
+            max_distance_m: 100.0,
+            mean_distance_m: 50.0,
+            frechet_m: 100.0,
+            hausdorff_m: 100.0,
+            osm_way_ids: vec![1],
+            osm_relation_ids: vec![1],
+            match_tier: None,
+            other_way_ids: Vec::new(),
+            category: DivergenceCategory::Reroute,
+            elevation: None,
+            counterpart_elevation: None,
+        }
+    }
+
+    fn kinds(changes: &[Change]) -> Vec<ChangeKind> {
+        changes.iter().map(|c| c.kind).collect()
+    }
+
+    #[test]
+    fn classifies_changes() {
+        let missing = DivergenceKind::MissingFromOsm;
+        // ~920m per 0.01° of longitude at 34°N
+        let old = vec![
+            divergence(missing, -118.00, -117.99),
+            divergence(missing, -117.90, -117.89),
+            divergence(missing, -117.80, -117.79),
+            divergence(missing, -117.70, -117.69),
+        ];
+        let new = vec![
+            divergence(missing, -118.00, -117.99),
+            divergence(missing, -117.90, -117.88),
+            divergence(missing, -117.80, -117.795),
+            divergence(missing, -117.60, -117.59),
+        ];
+
+        let changes = diff_divergences(&old, &new, 25.0, 50.0);
+
+        assert_eq!(
+            kinds(&changes),
+            vec![
+                ChangeKind::New,
+                ChangeKind::Resolved,
+                ChangeKind::Grown,
+                ChangeKind::Shrunk,
+                ChangeKind::Unchanged,
+            ]
+        );
+        let grown = &changes[2];
+        assert!((grown.length_change_m() - 920.0).abs() < 20.0);
+        assert!((grown.overlap_m - grown.old.as_ref().unwrap().length_m).abs() < 20.0);
+    }
+
+    #[test]
+    fn kinds_are_matched_separately() {
+        let old = vec![divergence(DivergenceKind::MissingFromOsm, -118.0, -117.99)];
+        let new = vec![divergence(DivergenceKind::StaleOsmWay, -118.0, -117.99)];
+
+        let changes = diff_divergences(&old, &new, 25.0, 50.0);
+        assert_eq!(kinds(&changes), vec![ChangeKind::New, ChangeKind::Resolved]);
+    }
+
+    #[test]
+    fn offset_beyond_tolerance_does_not_match() {
+        let old = vec![divergence(DivergenceKind::MissingFromOsm, -118.0, -117.99)];
+        let mut shifted = divergence(DivergenceKind::MissingFromOsm, -118.0, -117.99);
+        // ~55m north
+        shifted.geometry = LineString::from(vec![(-118.0, 34.0005), (-117.99, 34.0005)]);
+
+        assert_eq!(
+            kinds(&diff_divergences(&old, &[shifted.clone()], 25.0, 50.0)),
+            vec![ChangeKind::New, ChangeKind::Resolved]
+        );
+        assert_eq!(kinds(&diff_divergences(&old, &[shifted], 100.0, 50.0)), vec![ChangeKind::Unchanged]);
+    }
+}
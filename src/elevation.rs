@@ -0,0 +1,234 @@
+use anyhow::{bail, Context, Result};
+use gdal::spatial_ref::{AxisMappingStrategy, CoordTransform};
+use gdal::{Dataset, GeoTransform, GeoTransformEx};
+use geo::{Coord, Densify, Haversine, LineString};
+use rstar::RTree;
+use std::path::Path;
+
+use crate::compare::{haversine_point_to_segment, nearest, Divergence, PathIndex, PctaSegment};
+use crate::pcta::wgs84;
+
+/// PCTA Z values are only used for points this close to the PCTA (meters); anything further is
+/// off the trail and needs a DEM.
+const PCTA_MAX_DISTANCE_M: f64 = 1.0;
+
+/// Total climb and descent along a line, in meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ElevationProfile {
+    pub gain_m: f64,
+    pub loss_m: f64,
+}
+
+impl ElevationProfile {
+    /// Sum the rises and falls between consecutive elevations, or `None` if there are fewer
+    /// than two.
+    pub fn from_elevations(elevations: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let mut elevations = elevations.into_iter();
+        let mut previous = elevations.next()?;
+        let mut profile = None;
+        for z in elevations {
+            let p = profile.get_or_insert(ElevationProfile {
+                gain_m: 0.0,
+                loss_m: 0.0,
+            });
+            if z > previous {
+                p.gain_m += z - previous;
+            } else {
+                p.loss_m += previous - z;
+            }
+            previous = z;
+        }
+        profile
+    }
+}
+
+/// A digital elevation model read through GDAL, such as a GeoTIFF.
+///
+/// Tiles are read on demand, two by two pixels at a time, so the whole raster never has to
+/// fit in memory. GDAL datasets can't be shared between threads, so lookups are sequential.
+pub struct Dem {
+    dataset: Dataset,
+    /// From WGS84 longitude/latitude to the DEM's coordinate system.
+    to_dem: CoordTransform,
+    /// From the DEM's coordinate system to pixel coordinates.
+    to_pixel: GeoTransform,
+    size: (usize, usize),
+    no_data: Option<f64>,
+}
+
+impl Dem {
+    pub fn open(path: &Path) -> Result<Self> {
+        let dataset = Dataset::open(path).with_context(|| format!("Failed to open DEM {}", path.display()))?;
+        let size = dataset.raster_size();
+        if size.0 < 2 || size.1 < 2 {
+            bail!("DEM {} is smaller than 2x2 pixels", path.display());
+        }
+        let to_pixel = dataset
+            .geo_transform()
+            .and_then(|gt| gt.invert())
+            .context("DEM has no usable geotransform")?;
+        let no_data = dataset.rasterband(1).context("DEM has no raster band")?.no_data_value();
+
+        let Ok(mut dem_srs) = dataset.spatial_ref() else {
+            bail!("DEM {} has no coordinate reference system", path.display());
+        };
+        dem_srs.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+        let to_dem = CoordTransform::new(&wgs84()?, &dem_srs).context("Can't transform WGS84 to the DEM's CRS")?;
+
+        Ok(Self {
+            dataset,
+            to_dem,
+            to_pixel,
+            size,
+            no_data,
+        })
+    }
+
+    /// Elevation at `coord`, bilinearly interpolated between the four nearest pixel centers;
+    /// `None` outside the raster or next to a no-data pixel.
+    pub fn elevation_m(&self, coord: Coord<f64>) -> Option<f64> {
+        let (mut x, mut y) = ([coord.x], [coord.y]);
+        self.to_dem.transform_coords(&mut x, &mut y, &mut []).ok()?;
+        let (px, py) = self.to_pixel.apply(x[0], y[0]);
+
+        let (width, height) = (self.size.0 as f64, self.size.1 as f64);
+        if !(0.0..=width).contains(&px) || !(0.0..=height).contains(&py) {
+            return None;
+        }
+        // Pixel values are at pixel centers, and the window is kept inside the raster
+        let col = ((px - 0.5).floor() as isize).clamp(0, self.size.0 as isize - 2);
+        let row = ((py - 0.5).floor() as isize).clamp(0, self.size.1 as isize - 2);
+        let fx = (px - 0.5 - col as f64).clamp(0.0, 1.0);
+        let fy = (py - 0.5 - row as f64).clamp(0.0, 1.0);
+
+        let buffer = self
+            .dataset
+            .rasterband(1)
+            .and_then(|band| band.read_as::<f64>((col, row), (2, 2), (2, 2), None))
+            .ok()?;
+        let v = buffer.data();
+        if v.iter().any(|&z| z.is_nan() || Some(z) == self.no_data) {
+            return None;
+        }
+        let top = v[0] + fx * (v[1] - v[0]);
+        let bottom = v[2] + fx * (v[3] - v[2]);
+        Some(top + fy * (bottom - top))
+    }
+}
+
+/// Where elevations come from: the PCTA's own Z values for points on the PCTA, and otherwise
+/// the DEM, if there is one.
+pub struct ElevationLookup<'a> {
+    /// The PCTA geometry the Z values belong to ([`crate::compare::build_pcta_index`]).
+    pub pcta: &'a RTree<PctaSegment>,
+    pub dem: Option<&'a Dem>,
+}
+
+impl ElevationLookup<'_> {
+    pub fn elevation_m(&self, coord: Coord<f64>) -> Option<f64> {
+        nearest(self.pcta, coord)
+            .filter(|seg| haversine_point_to_segment(coord, seg.line.start, seg.line.end) <= PCTA_MAX_DISTANCE_M)
+            .and_then(|seg| seg.elevation_at(coord))
+            .or_else(|| self.dem?.elevation_m(coord))
+    }
+
+    /// Climb and descent along `ls`, looked up every `interval_m` meters and at each vertex.
+    /// Points with no elevation are skipped.
+    pub fn profile(&self, ls: &LineString<f64>, interval_m: f64) -> Option<ElevationProfile> {
+        let points = ls.densify::<Haversine>(interval_m);
+        ElevationProfile::from_elevations(points.0.iter().filter_map(|&coord| self.elevation_m(coord)))
+    }
+}
+
+/// Set the elevation profile of each divergence and of the stretch of `counterparts` it
+/// corresponds to (the OSM paths for `MissingFromOsm`, the PCTA for `StaleOsmWay`), so a reroute
+/// can be compared with the old trail it replaces.
+pub fn annotate_elevations(
+    divergences: &mut [Divergence],
+    counterparts: &PathIndex,
+    lookup: &ElevationLookup,
+    interval_m: f64,
+) {
+    for d in divergences {
+        d.elevation = lookup.profile(&d.geometry, interval_m);
+        d.counterpart_elevation = counterparts
+            .counterpart(&d.geometry.0)
+            .and_then(|other| lookup.profile(&other, interval_m));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::test_support::make_section;
+    use crate::compare::{build_pcta_index, PctaSection};
+
+    #[test]
+    fn gain_and_loss() {
+        let profile = ElevationProfile::from_elevations([1000.0, 1100.0, 1050.0, 1200.0, 1200.0]).unwrap();
+        assert_eq!(profile.gain_m, 250.0);
+        assert_eq!(profile.loss_m, 50.0);
+        assert_eq!(ElevationProfile::from_elevations([1000.0]), None);
+    }
+
+    #[test]
+    fn pcta_elevations_are_interpolated() {
+        // ~920m at 34°N, climbing 100m
+        let index = build_pcta_index(&[PctaSection {
+            elevations_m: vec![vec![1000.0, 1100.0]],
+            ..make_section("Test", vec![(-118.0, 34.0), (-117.99, 34.0)])
+        }]);
+        let lookup = ElevationLookup {
+            pcta: &index,
+            dem: None,
+        };
+
+        let z = lookup.elevation_m(Coord { x: -117.995, y: 34.0 }).unwrap();
+        assert!((z - 1050.0).abs() < 0.1, "{}", z);
+        // 55m off the trail, with no DEM to fall back on
+        assert_eq!(lookup.elevation_m(Coord { x: -117.995, y: 34.0005 }), None);
+
+        let there = LineString::from(vec![(-118.0, 34.0), (-117.99, 34.0)]);
+        let profile = lookup.profile(&there, 25.0).unwrap();
+        assert!((profile.gain_m - 100.0).abs() < 0.1 && profile.loss_m.abs() < 0.1, "{:?}", profile);
+    }
+
+    #[test]
+    fn dem_interpolates_between_pixel_centers() {
+        // 4x3 pixels of 30m in UTM zone 11N from (400000, 3763000), rising 10m a column and 100m
+        // a row, with the bottom right pixel no-data. Points are given by their UTM coordinates.
+        let dem = Dem::open(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/dem_utm11n.tif")).unwrap();
+        let cases = [
+            // (400015, 3762985), the center of the top left pixel
+            (-118.082710093, 34.002711789, Some(1000.0)),
+            // (400022.5, 3762985), a quarter of the way to the next pixel center
+            (-118.082628891, 34.002712504, Some(1002.5)),
+            // (400030, 3762970), between the top left four pixel centers
+            (-118.082545972, 34.002577959, Some(1055.0)),
+            // (400005, 3762995), in the raster but outside its pixel centers: the nearest edge
+            (-118.082819508, 34.002801009, Some(1000.0)),
+            // (400100, 3762940), next to the no-data pixel
+            (-118.081784651, 34.002314107, None),
+            // (399000, 3762985), 1km west of the raster
+            (-118.093699502, 34.002614565, None),
+        ];
+        for (lon, lat, expected) in cases {
+            let z = dem.elevation_m(Coord { x: lon, y: lat });
+            let matches = match (z, expected) {
+                (Some(z), Some(expected)) => (z - expected).abs() < 0.01,
+                (z, expected) => z == expected,
+            };
+            assert!(matches, "({}, {}): {:?}, expected {:?}", lon, lat, z, expected);
+        }
+    }
+
+    #[test]
+    fn sections_without_z_have_no_elevation() {
+        let index = build_pcta_index(&[make_section("Test", vec![(-118.0, 34.0), (-117.99, 34.0)])]);
+        let lookup = ElevationLookup {
+            pcta: &index,
+            dem: None,
+        };
+        assert_eq!(lookup.elevation_m(Coord { x: -117.995, y: 34.0 }), None);
+    }
+}
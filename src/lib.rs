@@ -1,4 +1,6 @@
 pub mod compare;
+pub mod diff;
+pub mod elevation;
 pub mod osm;
 pub mod output;
 pub mod pcta;
@@ -1,21 +1,82 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use pct_diff::compare::{build_index, find_divergences};
-use pct_diff::osm::{fetch_relation_ways, FetchProgress};
-use pct_diff::output::to_geojson;
-use pct_diff::pcta::load_pcta_gdb;
+use pct_diff::compare::{
+    assemble_paths, build_pcta_index, build_tiered_index, classify_match_tiers, estimate_section_offsets,
+    find_divergences, find_stale_osm_ways, CompareMode, CompareOptions, MatchTier, PathIndex, PctaSection,
+    METERS_PER_MILE,
+};
+use pct_diff::diff::diff_divergences;
+use pct_diff::elevation::{annotate_elevations, Dem, ElevationLookup};
+use pct_diff::osm::{
+    fetch_relation_ways, fetch_relation_ways_overpass, read_relation_ways, read_trail_ways, CacheMode, CachePolicy,
+    FetchOptions, FetchProgress, OVERPASS_URL,
+};
+use pct_diff::output::{
+    changes_table, changes_to_geojson, profile_to_csv, profile_to_geojson, read_geojson, route_findings_to_geojson,
+    to_geojson,
+};
+use pct_diff::pcta::{load_pcta, GdalSource, LayerSelector, LoadOptions, SectionFilter};
 
 #[derive(Parser)]
-#[command(about = "Find PCTA reroutes not yet in OpenStreetMap")]
+#[command(about = "Find PCTA reroutes not yet in OpenStreetMap", args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Path to Full_PCT.gdb.zip
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to Full_PCT.gdb.zip, or any vector data GDAL can read (Shapefile, GeoPackage, KML,
+    /// GPX, GeoJSON, an unzipped GDB...)
     #[arg(long, default_value = "Full_PCT.gdb.zip")]
     pcta: PathBuf,
 
+    /// Read only this layer of the PCTA data, by name or index (repeatable); by default every
+    /// layer is read
+    #[arg(long)]
+    pcta_layer: Vec<LayerSelector>,
+
+    /// OGR SQL where-clause PCTA features must match, e.g. "Section_Name LIKE 'CA%'"
+    #[arg(long)]
+    pcta_where: Option<String>,
+
+    /// Attribute holding the PCTA section name; by default the first of Section_Name, Section,
+    /// SECTION, Name, NAME or name
+    #[arg(long)]
+    section_field: Option<String>,
+
+    /// Attribute holding the PCTA state; by default the first of State, STATE or state
+    #[arg(long)]
+    state_field: Option<String>,
+
+    /// Attribute holding the PCTA segment ID
+    #[arg(long)]
+    segment_id_field: Option<String>,
+
+    /// Attribute saying whether a PCTA feature is the official route or an alternate (Yes/No,
+    /// True/False, 1/0, Official/Alternate); by default the first of Official, OFFICIAL or official
+    #[arg(long)]
+    official_field: Option<String>,
+
+    /// Compare only PCTA sections marked as the official route, leaving out alternates
+    #[arg(long)]
+    only_official: bool,
+
+    /// Compare only PCTA sections in this state, e.g. CA (repeatable)
+    #[arg(long)]
+    state: Vec<String>,
+
+    /// PCTA linestring ends within this distance (meters) of each other are joined when
+    /// stitching the sections into one route; further apart, they're reported as a gap
+    #[arg(long, default_value_t = 5.0)]
+    snap_tolerance: f64,
+
+    /// Also write the gaps and overlaps between PCTA linestrings here, as GeoJSON
+    #[arg(long)]
+    route_output: Option<PathBuf>,
+
     /// OSM relation ID for the PCT
     #[arg(long, default_value_t = 1225378)]
     relation: u64,
@@ -24,6 +85,20 @@ struct Cli {
     #[arg(long, default_value_t = 10.0)]
     threshold: f64,
 
+    /// Distance (meters) a divergent run must fall back under before it ends; defaults to
+    /// --threshold. Lower values keep runs that briefly dip under the threshold together
+    #[arg(long)]
+    exit_threshold: Option<f64>,
+
+    /// Merge divergent runs separated by at most this many meters
+    #[arg(long, default_value_t = 0.0)]
+    max_gap: f64,
+
+    /// Segments within this distance (meters) of a sample are candidates for its match, chosen
+    /// by distance, heading and continuity with the previous sample's match
+    #[arg(long, default_value_t = 50.0)]
+    search_radius: f64,
+
     /// Minimum divergent segment length (meters)
     #[arg(long, default_value_t = 500.0)]
     min_length: f64,
@@ -32,18 +107,157 @@ struct Cli {
     #[arg(long, default_value_t = 25.0)]
     sample_interval: f64,
 
+    /// How samples are scored: distance to the nearest segment, or discrete Fréchet distance
+    /// of a window around each sample against the matching stretch of the other dataset
+    #[arg(long, value_enum, default_value_t = Mode::Nearest)]
+    mode: Mode,
+
+    /// Window length (meters) compared around each sample with --mode frechet
+    #[arg(long, default_value_t = 200.0)]
+    frechet_window: f64,
+
+    /// Move each PCTA section by its best-fit offset from OSM before comparing, so a steady
+    /// imagery or GPS offset isn't reported as a divergence
+    #[arg(long)]
+    correct_offsets: bool,
+
+    /// DEM (e.g. a GeoTIFF) giving elevations off the PCTA, so OSM geometry gets an elevation
+    /// profile too. PCTA elevations come from its Z values
+    #[arg(long)]
+    dem: Option<PathBuf>,
+
     /// Output GeoJSON path
     #[arg(long, default_value = "divergences.geojson")]
     output: PathBuf,
 
+    /// Also write every sample's distance profile here: CSV if the path ends in .csv,
+    /// otherwise GeoJSON points
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
     /// Cache directory for OSM data
     #[arg(long, default_value = ".cache")]
     cache_dir: PathBuf,
+
+    /// Revalidate cached OSM responses older than this many hours (0 = always)
+    #[arg(long, default_value_t = 24.0)]
+    cache_max_age: f64,
+
+    /// Revalidate every cached OSM response, ignoring --cache-max-age
+    #[arg(long, conflicts_with = "offline")]
+    refresh: bool,
+
+    /// Use only cached OSM responses; fail if one is missing
+    #[arg(long)]
+    offline: bool,
+
+    /// Read OSM data from a local .osm.pbf or .osm extract instead of the OSM API
+    #[arg(long, conflicts_with = "overpass")]
+    osm_file: Option<PathBuf>,
+
+    /// Also read highway=path/footway/track ways outside the relation from this .osm.pbf or
+    /// .osm extract, to tell trails missing from the relation apart from unmapped trails
+    #[arg(long)]
+    other_paths: Option<PathBuf>,
+
+    /// Fetch OSM data with a single Overpass query instead of the OSM API
+    #[arg(long)]
+    overpass: bool,
+
+    /// Overpass API endpoint used with --overpass
+    #[arg(long, default_value = OVERPASS_URL)]
+    overpass_url: String,
+
+    /// Number of OSM relations fetched concurrently
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+
+    /// Maximum OSM API requests per second across all workers (0 for no limit)
+    #[arg(long, default_value_t = 2.0)]
+    requests_per_second: f64,
+
+    /// Retries for failed OSM requests (429, 5xx, connection errors), with exponential backoff
+    #[arg(long, default_value_t = 4)]
+    max_retries: u32,
+}
+
+/// `--mode` values, one per [`CompareMode`].
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    /// Distance from each sample to the nearest segment
+    Nearest,
+    /// Discrete Fréchet distance between a window around each sample and the corresponding
+    /// stretch of the other dataset
+    Frechet,
+}
+
+impl From<Mode> for CompareMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Nearest => CompareMode::Nearest,
+            Mode::Frechet => CompareMode::Frechet,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compare two divergence outputs and report what changed between them
+    Diff {
+        /// Divergences GeoJSON from the earlier run
+        old: PathBuf,
+
+        /// Divergences GeoJSON from the later run
+        new: PathBuf,
+
+        /// Divergences closer than this (meters) are treated as the same place
+        #[arg(long, default_value_t = 25.0)]
+        tolerance: f64,
+
+        /// Length change (meters) below which a matched divergence counts as unchanged
+        #[arg(long, default_value_t = 50.0)]
+        min_change: f64,
+
+        /// Output change report GeoJSON path
+        #[arg(long, default_value = "changes.geojson")]
+        output: PathBuf,
+
+        /// Output change report text table path
+        #[arg(long, default_value = "changes.txt")]
+        table: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    if let Some(Command::Diff {
+        old,
+        new,
+        tolerance,
+        min_change,
+        output,
+        table,
+    }) = &cli.command
+    {
+        return run_diff(old, new, *tolerance, *min_change, output, table);
+    }
+    if let Some(exit_threshold) = cli.exit_threshold
+        && exit_threshold > cli.threshold
+    {
+        bail!("--exit-threshold ({}) must not exceed --threshold ({})", exit_threshold, cli.threshold);
+    }
+    ensure!(cli.sample_interval > 0.0, "--sample-interval must be positive");
+    let cache_max_age = Duration::try_from_secs_f64(cli.cache_max_age.max(0.0) * 3600.0)
+        .with_context(|| format!("--cache-max-age {} is out of range", cli.cache_max_age))?;
+    let requests_per_second = cli.requests_per_second;
+    ensure!(
+        requests_per_second == 0.0
+            || requests_per_second.is_finite() && Duration::try_from_secs_f64(1.0 / requests_per_second).is_ok(),
+        "--requests-per-second {} is out of range",
+        requests_per_second
+    );
+
     let spinner_style = ProgressStyle::with_template("{spinner:.cyan} {msg}").unwrap();
     let bar_style = ProgressStyle::with_template("{spinner:.cyan} {msg} [{bar:40}] {pos}/{len}")
         .unwrap()
@@ -54,49 +268,259 @@ fn main() -> Result<()> {
     pb.set_style(spinner_style.clone());
     pb.enable_steady_tick(Duration::from_millis(100));
     pb.set_message(format!("Loading PCTA data from {}...", cli.pcta.display()));
-    let pcta_sections = load_pcta_gdb(&cli.pcta)?;
+    let pcta_source = GdalSource {
+        options: LoadOptions {
+            layers: cli.pcta_layer.clone(),
+            where_clause: cli.pcta_where.clone(),
+            section_field: cli.section_field.clone(),
+            state_field: cli.state_field.clone(),
+            official_field: cli.official_field.clone(),
+            segment_id_field: cli.segment_id_field.clone(),
+        },
+        ..GdalSource::new(&cli.pcta)
+    };
+    let section_filter = SectionFilter {
+        only_official: cli.only_official,
+        states: cli.state.clone(),
+    };
+    let route = load_pcta(&pcta_source, &section_filter, cli.snap_tolerance)?;
+    let pcta_sections = route.sections;
     pb.finish_with_message(format!("Loaded {} PCTA sections", pcta_sections.len()));
+    if !route.unknown_official.is_empty() {
+        eprintln!(
+            "Warning: --only-official dropped {} PCTA sections with no official status: {}",
+            route.unknown_official.len(),
+            route.unknown_official.join(", ")
+        );
+    }
+    if route.findings.is_empty() {
+        println!("PCTA route is continuous");
+    } else {
+        println!("PCTA route has {} breaks:", route.findings.len());
+        for finding in &route.findings {
+            println!(
+                "  mile {:.1} - {:.0}m {} between {} and {}",
+                finding.trail_m / METERS_PER_MILE,
+                finding.length_m,
+                finding.kind.as_str(),
+                finding.from_section,
+                finding.to_section
+            );
+        }
+    }
+    if let Some(path) = &cli.route_output {
+        std::fs::write(path, serde_json::to_string_pretty(&route_findings_to_geojson(&route.findings))?)?;
+        println!("Wrote {}", path.display());
+    }
+    let dem = cli.dem.as_deref().map(Dem::open).transpose()?;
 
     // Fetch OSM data
     let pb = ProgressBar::new(0);
     pb.set_style(bar_style.clone());
     pb.enable_steady_tick(Duration::from_millis(100));
     pb.set_message(format!("Fetching OSM relation {}...", cli.relation));
-    let osm_lines = fetch_relation_ways(cli.relation, &cli.cache_dir, Some(&|event| match event {
+    let on_progress = |event| match event {
         FetchProgress::SubRelationsFound(count) => pb.set_length(count as u64),
-        FetchProgress::SubRelationFetched(_) => pb.inc(1),
-    }))?;
-    pb.finish_with_message(format!("Fetched {} OSM ways", osm_lines.len()));
+        FetchProgress::SubRelationFetched(_) | FetchProgress::SubRelationFailed(_) => pb.inc(1),
+    };
+    let cache_policy = CachePolicy {
+        max_age: Some(cache_max_age),
+        mode: if cli.offline {
+            CacheMode::Offline
+        } else if cli.refresh {
+            CacheMode::Refresh
+        } else {
+            CacheMode::Normal
+        },
+    };
+    let fetch_options = FetchOptions {
+        workers: cli.workers,
+        requests_per_second: cli.requests_per_second,
+        max_retries: cli.max_retries,
+        ..FetchOptions::default()
+    };
+    let fetched = match &cli.osm_file {
+        Some(path) => read_relation_ways(path, cli.relation, Some(&on_progress))?,
+        None if cli.overpass => fetch_relation_ways_overpass(
+            cli.relation,
+            &cli.overpass_url,
+            &cli.cache_dir,
+            &cache_policy,
+            &fetch_options,
+            Some(&on_progress),
+        )?,
+        None => fetch_relation_ways(
+            cli.relation,
+            &cli.cache_dir,
+            &cache_policy,
+            &fetch_options,
+            Some(&on_progress),
+        )?,
+    };
+    let osm_ways = fetched.ways;
+    let max_depth = osm_ways.iter().map(|w| w.depth()).max().unwrap_or(0);
+    pb.finish_with_message(format!(
+        "Fetched {} OSM ways (relation tree depth {})",
+        osm_ways.len(),
+        max_depth
+    ));
+
+    let other_ways = match &cli.other_paths {
+        Some(path) => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(spinner_style.clone());
+            pb.enable_steady_tick(Duration::from_millis(100));
+            pb.set_message(format!("Reading other trails from {}...", path.display()));
+            let relation_way_ids: HashSet<u64> = osm_ways.iter().map(|w| w.id).collect();
+            let ways = read_trail_ways(path, &relation_way_ids)?;
+            pb.finish_with_message(format!("Read {} other OSM trail ways", ways.len()));
+            Some(ways)
+        }
+        None => None,
+    };
 
     // Build spatial index
     let pb = ProgressBar::new_spinner();
     pb.set_style(spinner_style);
     pb.enable_steady_tick(Duration::from_millis(100));
     pb.set_message("Building spatial index...");
-    let index = build_index(&osm_lines, Some(&pb));
+    let index = build_tiered_index(&osm_ways, other_ways.as_deref(), Some(&pb));
+    let osm_paths = PathIndex::new(assemble_paths(&osm_ways));
     pb.finish_with_message("Spatial index built");
 
+    let options = CompareOptions {
+        threshold_m: cli.threshold,
+        exit_threshold_m: cli.exit_threshold,
+        max_gap_m: cli.max_gap,
+        search_radius_m: cli.search_radius,
+        min_length_m: cli.min_length,
+        sample_interval_m: cli.sample_interval,
+        mode: cli.mode.into(),
+        frechet_window_m: cli.frechet_window,
+        profile: cli.profile.is_some(),
+    };
+
+    // Per-section offsets between the datasets
+    let section_offsets = cli.correct_offsets.then(|| {
+        let section_offsets = estimate_section_offsets(&pcta_sections, &index.relation, &options);
+        println!("Section offsets (PCTA to OSM):");
+        for offset in &section_offsets {
+            if offset.is_reliable() {
+                println!(
+                    "  {}: {:.1}m ({:+.1}m east, {:+.1}m north), RMS {:.1}m -> {:.1}m over {}/{} samples",
+                    offset.section_name,
+                    offset.magnitude_m(),
+                    offset.east_m,
+                    offset.north_m,
+                    offset.rms_before_m,
+                    offset.rms_after_m,
+                    offset.matched,
+                    offset.samples
+                );
+            } else {
+                println!(
+                    "  {}: too few samples near OSM ({}/{})",
+                    offset.section_name, offset.matched, offset.samples
+                );
+            }
+        }
+        section_offsets
+    });
+
     // Find divergences
     let pb = ProgressBar::new(pcta_sections.len() as u64);
-    pb.set_style(bar_style);
+    pb.set_style(bar_style.clone());
     pb.enable_steady_tick(Duration::from_millis(100));
     pb.set_message("Comparing geometries...");
-    let divergences = find_divergences(
+    let forward = find_divergences(
         &pcta_sections,
-        &index,
-        cli.threshold,
-        cli.min_length,
-        cli.sample_interval,
+        &index.relation,
+        &osm_paths,
+        &options,
+        section_offsets.as_deref(),
         Some(&pb),
     );
+    let mut divergences = forward.divergences;
+    let mut profile = forward.profile;
+    classify_match_tiers(&mut divergences, &index, &options);
+    // Divergence geometry is back in the original PCTA coordinates, so look up Z values there
+    let elevation_index = build_pcta_index(&pcta_sections);
+    let lookup = ElevationLookup {
+        pcta: &elevation_index,
+        dem: dem.as_ref(),
+    };
+    annotate_elevations(&mut divergences, &osm_paths, &lookup, options.sample_interval_m);
     pb.finish_with_message(format!("Found {} divergent segments", divergences.len()));
 
+    // Reverse pass: OSM ways with no PCTA counterpart
+    let pb = ProgressBar::new(osm_ways.len() as u64);
+    pb.set_style(bar_style);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb.set_message("Checking OSM ways against PCTA...");
+    // Compare OSM against the corrected PCTA too, so the offset isn't reported as stale ways
+    let pcta_sections: Vec<PctaSection> = match &section_offsets {
+        Some(offsets) => pcta_sections
+            .iter()
+            .zip(offsets)
+            .map(|(section, offset)| {
+                if offset.is_reliable() {
+                    offset.apply_to_section(section)
+                } else {
+                    section.clone()
+                }
+            })
+            .collect(),
+        None => pcta_sections,
+    };
+    let pcta_index = build_pcta_index(&pcta_sections);
+    let pcta_paths = PathIndex::from_sections(&pcta_sections);
+    let mut stale = find_stale_osm_ways(
+        &osm_ways,
+        &pcta_sections,
+        &pcta_index,
+        &pcta_paths,
+        &options,
+        Some(&pb),
+    );
+    let lookup = ElevationLookup {
+        pcta: &pcta_index,
+        dem: dem.as_ref(),
+    };
+    annotate_elevations(&mut stale.divergences, &pcta_paths, &lookup, options.sample_interval_m);
+    pb.finish_with_message(format!("Found {} stale OSM segments", stale.divergences.len()));
+
+    divergences.extend(stale.divergences);
+    profile.extend(stale.profile);
+
     // Results summary
     for d in &divergences {
         println!(
-            "  {} - {:.0}m long, max {:.0}m, mean {:.0}m off",
-            d.section_name, d.length_m, d.max_distance_m, d.mean_distance_m
+            "  [{}/{}] {} mile {:.1}-{:.1} - {:.0}m long, max {:.0}m, mean {:.0}m off, Fréchet {:.0}m, \
+             nearest ways {:?}",
+            d.kind.as_str(),
+            d.category.as_str(),
+            d.section_name,
+            d.start_m / METERS_PER_MILE,
+            d.end_m / METERS_PER_MILE,
+            d.length_m,
+            d.max_distance_m,
+            d.mean_distance_m,
+            d.frechet_m,
+            d.osm_way_ids
         );
+        match d.match_tier {
+            Some(MatchTier::OtherPath) => {
+                println!("      follows OSM ways {:?}, which aren't in the relation", d.other_way_ids)
+            }
+            Some(MatchTier::Relation) => println!("      follows relation ways, but from a different direction"),
+            None => {}
+        }
+        if let Some(elevation) = d.elevation {
+            let counterpart = d.counterpart_elevation.map_or(String::new(), |c| {
+                format!(" (+{:.0}m/-{:.0}m along the other dataset)", c.gain_m, c.loss_m)
+            });
+            println!("      climbs +{:.0}m/-{:.0}m{}", elevation.gain_m, elevation.loss_m, counterpart);
+        }
     }
 
     let geojson = to_geojson(&divergences);
@@ -104,5 +528,49 @@ fn main() -> Result<()> {
     std::fs::write(&cli.output, json)?;
     println!("Wrote {}", cli.output.display());
 
+    if let Some(path) = &cli.profile {
+        let contents = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+            profile_to_csv(&profile)
+        } else {
+            serde_json::to_string(&profile_to_geojson(&profile))?
+        };
+        std::fs::write(path, contents)?;
+        println!("Wrote {} samples to {}", profile.len(), path.display());
+    }
+
+    if !fetched.failed.is_empty() {
+        println!(
+            "Warning: {} OSM relations could not be fetched; their ways are missing from the comparison:",
+            fetched.failed.len()
+        );
+        for failure in &fetched.failed {
+            let path: Vec<String> = failure.relation_path.iter().map(|id| id.to_string()).collect();
+            println!("  relation {} - {}", path.join(" > "), failure.error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Compare two divergence outputs and write the change report.
+fn run_diff(old: &Path, new: &Path, tolerance_m: f64, min_change_m: f64, output: &Path, table: &Path) -> Result<()> {
+    let read = |path: &Path| {
+        let json = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        read_geojson(&json).with_context(|| format!("Failed to parse {}", path.display()))
+    };
+    let old_divergences = read(old)?;
+    let new_divergences = read(new)?;
+
+    let changes = diff_divergences(&old_divergences, &new_divergences, tolerance_m, min_change_m);
+
+    let text = changes_table(&changes);
+    print!("{}", text);
+    std::fs::write(table, &text)?;
+    println!("Wrote {}", table.display());
+
+    let json = serde_json::to_string_pretty(&changes_to_geojson(&changes))?;
+    std::fs::write(output, json)?;
+    println!("Wrote {}", output.display());
+
     Ok(())
 }
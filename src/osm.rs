@@ -1,19 +1,92 @@
-use anyhow::{Context, Result};
+mod cache;
+mod http;
+mod pbf;
+mod xml;
+
+pub use cache::{read_metadata, CacheMetadata, CacheMode, CachePolicy};
+pub use http::FetchOptions;
+
+use anyhow::{bail, Context, Result};
 use geo::{Coord, LineString};
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+use http::HttpClient;
 
 const OSM_API_BASE: &str = "https://api.openstreetmap.org/api/0.6";
 
+/// Default public Overpass API endpoint.
+pub const OVERPASS_URL: &str = "https://overpass-api.de/api/interpreter";
+
+/// `highway` values read by [`read_trail_ways`].
+pub const TRAIL_HIGHWAYS: &[&str] = &["path", "footway", "track"];
+
+/// An OSM way resolved to coordinates, with the identifiers needed to find it again.
+#[derive(Debug, Clone)]
+pub struct OsmWay {
+    pub id: u64,
+    /// Relations from the top-level relation down to the one this way is a direct member of.
+    /// Empty for ways read outside the relation by [`read_trail_ways`].
+    pub relation_path: Vec<u64>,
+    /// Node IDs for each coordinate in `geometry` (nodes without coordinates are dropped).
+    pub node_ids: Vec<u64>,
+    pub tags: HashMap<String, String>,
+    pub geometry: LineString<f64>,
+}
+
+impl OsmWay {
+    pub fn url(&self) -> String {
+        way_url(self.id)
+    }
+
+    /// The relation this way is a direct member of, if it was read through one.
+    pub fn relation_id(&self) -> Option<u64> {
+        self.relation_path.last().copied()
+    }
+
+    /// Number of relation levels above this way (1 for a direct member of the top-level relation).
+    pub fn depth(&self) -> usize {
+        self.relation_path.len()
+    }
+}
+
+/// Link to a way on openstreetmap.org.
+pub fn way_url(id: u64) -> String {
+    format!("https://www.openstreetmap.org/way/{}", id)
+}
+
 pub enum FetchProgress {
+    /// Total number of relations discovered in the tree so far.
     SubRelationsFound(usize),
     SubRelationFetched(u64),
+    /// A relation could not be fetched; it and everything below it are skipped.
+    SubRelationFailed(u64),
+}
+
+/// The ways found in a relation tree, plus the relations that could not be fetched.
+#[derive(Debug, Default)]
+pub struct RelationWays {
+    pub ways: Vec<OsmWay>,
+    pub failed: Vec<FailedRelation>,
+}
+
+/// A relation whose fetch failed after retries.
+#[derive(Debug)]
+pub struct FailedRelation {
+    /// Relations from the top-level relation down to the one that failed.
+    pub relation_path: Vec<u64>,
+    pub error: String,
 }
 
 #[derive(Debug, Deserialize)]
 struct RelationResponse {
     elements: Vec<Element>,
+    /// Set by Overpass when a query fails or times out.
+    #[serde(default)]
+    remark: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,22 +100,35 @@ enum Element {
     },
     #[serde(rename = "way")]
     Way {
-        #[allow(dead_code)]
         id: u64,
         nodes: Option<Vec<u64>>,
-        #[allow(dead_code)]
-        tags: Option<serde_json::Value>,
+        tags: Option<HashMap<String, String>>,
     },
     #[serde(rename = "relation")]
     Relation {
-        #[allow(dead_code)]
         id: u64,
         members: Option<Vec<RelationMember>>,
         #[allow(dead_code)]
-        tags: Option<serde_json::Value>,
+        tags: Option<HashMap<String, String>>,
     },
 }
 
+impl Element {
+    fn id(&self) -> u64 {
+        match self {
+            Element::Node { id, .. } | Element::Way { id, .. } | Element::Relation { id, .. } => *id,
+        }
+    }
+}
+
+/// Element types, used to let file readers skip blocks that a pass doesn't need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ElementType {
+    Node,
+    Way,
+    Relation,
+}
+
 #[derive(Debug, Deserialize)]
 struct RelationMember {
     #[serde(rename = "type")]
@@ -53,114 +139,459 @@ struct RelationMember {
     role: Option<String>,
 }
 
-/// Fetch all OSM linestrings for a relation, using cached responses when available.
+/// Fetch all OSM ways for a relation, using cached responses according to `cache_policy`.
+///
+/// Relations are fetched level by level with `fetch_options.workers` concurrent requests.
+/// A relation that still fails after retries is reported in [`RelationWays::failed`]
+/// instead of aborting the run; only a failure of the top-level relation is an error.
 pub fn fetch_relation_ways(
     relation_id: u64,
     cache_dir: &Path,
+    cache_policy: &CachePolicy,
+    fetch_options: &FetchOptions,
     on_progress: Option<&dyn Fn(FetchProgress)>,
-) -> Result<Vec<LineString<f64>>> {
+) -> Result<RelationWays> {
     std::fs::create_dir_all(cache_dir)?;
-    let client = reqwest::blocking::Client::builder()
-        .user_agent("pct-diff/0.1 (PCT reroute detection tool)")
-        .build()?;
+    let client = HttpClient::new(fetch_options)?;
 
-    // Step 1: Get the top-level relation to find sub-relation IDs
-    let top_json = fetch_cached(
-        &client,
-        &format!("{}/relation/{}.json", OSM_API_BASE, relation_id),
-        &cache_dir.join(format!("relation_{}.json", relation_id)),
-    )?;
+    // Fetch every relation in the tree; each full response holds that relation's direct members
+    let (ways, failed) = walk_relation_tree(relation_id, fetch_options.workers, on_progress, |path| {
+        let id = path[path.len() - 1];
+        let full_json = cache::fetch_cached(
+            &client,
+            &format!("{}/relation/{}/full.json", OSM_API_BASE, id),
+            &cache_dir.join(format!("relation_{}_full.json", id)),
+            id,
+            cache_policy,
+        )?;
 
-    let top: RelationResponse =
-        serde_json::from_str(&top_json).context("Failed to parse top-level relation JSON")?;
+        parse_full(&full_json, path).with_context(|| format!("Failed to parse full response for relation {}", id))
+    })?;
 
-    let sub_relation_ids: Vec<u64> = top
-        .elements
-        .iter()
-        .filter_map(|e| match e {
-            Element::Relation { members, .. } => members.as_ref(),
-            _ => None,
-        })
-        .flat_map(|members| members.iter())
-        .filter(|m| m.member_type == "relation")
-        .map(|m| m.member_ref)
-        .collect();
+    Ok(RelationWays {
+        ways: ways.into_iter().flatten().collect(),
+        failed,
+    })
+}
+
+/// Walk the relation tree below `root` breadth-first, visiting each relation once so that
+/// reference cycles terminate.
+///
+/// `visit` is called with the path from `root` to each relation (inclusive) and returns a
+/// value for that relation plus its member relation IDs in member order. Each level of the
+/// tree is visited by up to `workers` threads. Values are returned in traversal order;
+/// relations whose visit failed are returned separately, except for `root`, whose failure
+/// is an error.
+fn walk_relation_tree<T: Send>(
+    root: u64,
+    workers: usize,
+    on_progress: Option<&dyn Fn(FetchProgress)>,
+    visit: impl Fn(&[u64]) -> Result<(T, Vec<u64>)> + Sync,
+) -> Result<(Vec<T>, Vec<FailedRelation>)> {
+    let mut visited = HashSet::from([root]);
+    let mut level = vec![vec![root]];
+    let mut values = Vec::new();
+    let mut failed = Vec::new();
 
     if let Some(cb) = &on_progress {
-        cb(FetchProgress::SubRelationsFound(sub_relation_ids.len()));
+        cb(FetchProgress::SubRelationsFound(visited.len()));
     }
 
-    // Step 2: Fetch each sub-relation's full data
-    let mut all_lines = Vec::new();
+    while !level.is_empty() {
+        let results = visit_level(&level, workers, &visit, |path, result| {
+            if let Some(cb) = &on_progress {
+                let id = path[path.len() - 1];
+                cb(match result {
+                    Ok(_) => FetchProgress::SubRelationFetched(id),
+                    Err(_) => FetchProgress::SubRelationFailed(id),
+                });
+            }
+        });
 
-    for &sub_id in &sub_relation_ids {
-        let full_json = fetch_cached(
-            &client,
-            &format!("{}/relation/{}/full.json", OSM_API_BASE, sub_id),
-            &cache_dir.join(format!("relation_{}_full.json", sub_id)),
-        )?;
+        let mut next_level = Vec::new();
+        for (path, result) in level.into_iter().zip(results) {
+            match result {
+                Ok((value, sub_relation_ids)) => {
+                    for sub_id in sub_relation_ids {
+                        if visited.insert(sub_id) {
+                            let mut sub_path = path.clone();
+                            sub_path.push(sub_id);
+                            next_level.push(sub_path);
+                        }
+                    }
+                    values.push(value);
+                }
+                Err(e) if path.len() == 1 => return Err(e),
+                Err(e) => failed.push(FailedRelation {
+                    relation_path: path,
+                    error: format!("{:#}", e),
+                }),
+            }
+        }
+
+        if let Some(cb) = &on_progress
+            && !next_level.is_empty()
+        {
+            cb(FetchProgress::SubRelationsFound(visited.len()));
+        }
+        level = next_level;
+    }
 
-        let lines = parse_full_response(&full_json)
-            .with_context(|| format!("Failed to parse full response for relation {}", sub_id))?;
+    Ok((values, failed))
+}
 
-        all_lines.extend(lines);
+/// Run `visit` over `paths` on up to `workers` threads, returning results in input order.
+/// `on_done` is called on the calling thread as each result arrives.
+fn visit_level<R: Send>(
+    paths: &[Vec<u64>],
+    workers: usize,
+    visit: &(impl Fn(&[u64]) -> R + Sync),
+    mut on_done: impl FnMut(&[u64], &R),
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    let mut results: Vec<Option<R>> = paths.iter().map(|_| None).collect();
 
-        if let Some(cb) = &on_progress {
-            cb(FetchProgress::SubRelationFetched(sub_id));
+    std::thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, paths.len()) {
+            let tx = tx.clone();
+            let next = &next;
+            scope.spawn(move || {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = paths.get(i) else {
+                        break;
+                    };
+                    if tx.send((i, visit(path))).is_err() {
+                        break;
+                    }
+                }
+            });
         }
+        drop(tx);
+
+        for (i, result) in rx {
+            on_done(&paths[i], &result);
+            results[i] = Some(result);
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|r| r.expect("every path is visited exactly once"))
+        .collect()
+}
+
+/// Read all ways in a relation tree from a local `.osm.pbf` or `.osm` XML extract.
+///
+/// Produces the same ways as [`fetch_relation_ways`] without touching the network. The file is
+/// scanned for relations, then ways, then nodes, so only the elements belonging to the relation
+/// are held in memory; a PBF file's blobs are only decoded again if they can hold one of those
+/// ways or nodes. Relations missing from the extract are skipped.
+pub fn read_relation_ways(
+    path: &Path,
+    relation_id: u64,
+    on_progress: Option<&dyn Fn(FetchProgress)>,
+) -> Result<RelationWays> {
+    let mut extract = Extract::open(path)?;
+    let mut store = ElementStore::default();
+
+    // Pass 1: relation membership
+    extract.scan(ElementType::Relation, None, &mut |element| store.insert(element))?;
+    if !store.relations.contains_key(&relation_id) {
+        bail!("Relation {} not found in {}", relation_id, path.display());
     }
 
-    Ok(all_lines)
+    // Pass 2: ways
+    let memberships = store.way_memberships(relation_id, on_progress)?;
+    let wanted_ways: HashSet<u64> = memberships.iter().map(|(_, way_id)| *way_id).collect();
+    extract.scan(ElementType::Way, Some(&wanted_ways), &mut |element| store.insert(element))?;
+
+    // Pass 3: nodes
+    store.load_way_nodes(&mut extract)?;
+
+    Ok(RelationWays {
+        ways: store.resolve_ways(&memberships),
+        failed: Vec::new(),
+    })
 }
 
-/// Fetch a URL, using a cached file if it exists.
-fn fetch_cached(
-    client: &reqwest::blocking::Client,
-    url: &str,
-    cache_path: &Path,
-) -> Result<String> {
-    if cache_path.exists() {
-        return std::fs::read_to_string(cache_path).context("Failed to read cache file");
+/// Read every `highway=path/footway/track` way (see [`TRAIL_HIGHWAYS`]) from a local extract,
+/// skipping the IDs in `exclude` (normally the relation's own members).
+///
+/// These are trails that exist in OSM but aren't part of the relation; the returned ways have
+/// an empty `relation_path`.
+pub fn read_trail_ways(path: &Path, exclude: &HashSet<u64>) -> Result<Vec<OsmWay>> {
+    let mut extract = Extract::open(path)?;
+    let mut store = ElementStore::default();
+    let mut way_ids = Vec::new();
+    extract.scan(ElementType::Way, None, &mut |element| {
+        if let Element::Way { id, tags: Some(tags), .. } = &element
+            && !exclude.contains(id)
+            && tags.get("highway").is_some_and(|h| TRAIL_HIGHWAYS.contains(&h.as_str()))
+        {
+            way_ids.push(*id);
+            store.insert(element);
+        }
+    })?;
+    store.load_way_nodes(&mut extract)?;
+
+    let memberships: Vec<(Vec<u64>, u64)> = way_ids.into_iter().map(|id| (Vec::new(), id)).collect();
+    Ok(store.resolve_ways(&memberships))
+}
+
+/// A local `.osm.pbf` or `.osm` XML extract, read one element type at a time.
+struct Extract {
+    path: PathBuf,
+    /// The open file and its blob index, for PBF extracts.
+    pbf: Option<pbf::PbfExtract>,
+}
+
+impl Extract {
+    fn open(path: &Path) -> Result<Self> {
+        let is_pbf = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with(".pbf"));
+        let pbf = is_pbf
+            .then(|| pbf::PbfExtract::open(path))
+            .transpose()
+            .with_context(|| format!("Failed to read OSM file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            pbf,
+        })
+    }
+
+    /// Stream elements of `element_type` to `visit`; with `wanted`, only those with these IDs.
+    fn scan(
+        &mut self,
+        element_type: ElementType,
+        wanted: Option<&HashSet<u64>>,
+        visit: &mut dyn FnMut(Element),
+    ) -> Result<()> {
+        match &mut self.pbf {
+            Some(pbf) => pbf.scan(element_type, wanted, visit),
+            None => xml::scan(&self.path, element_type, &mut |element| {
+                if wanted.is_none_or(|w| w.contains(&element.id())) {
+                    visit(element);
+                }
+            }),
+        }
+        .with_context(|| format!("Failed to read OSM file {}", self.path.display()))
     }
+}
+
+/// Fetch all OSM ways for a relation from an Overpass API endpoint with a single recursive query.
+///
+/// The response is cached in `cache_dir` like the OSM API responses.
+pub fn fetch_relation_ways_overpass(
+    relation_id: u64,
+    endpoint: &str,
+    cache_dir: &Path,
+    cache_policy: &CachePolicy,
+    fetch_options: &FetchOptions,
+    on_progress: Option<&dyn Fn(FetchProgress)>,
+) -> Result<RelationWays> {
+    std::fs::create_dir_all(cache_dir)?;
+    let client = HttpClient::new(fetch_options)?;
 
-    let response = client.get(url).send()?.error_for_status()?;
-    let body = response.text()?;
+    // The relation itself plus every member relation, way and node below it
+    let query = format!("[out:json][timeout:300];rel({});(._;>>;);out;", relation_id);
+    let url = reqwest::Url::parse_with_params(endpoint, &[("data", &query)])
+        .with_context(|| format!("Invalid Overpass endpoint {}", endpoint))?;
+    let cache_path = cache_dir.join(format!("overpass_relation_{}.json", relation_id));
+    let json = cache::fetch_cached(&client, url.as_str(), &cache_path, relation_id, cache_policy)?;
 
-    std::fs::write(cache_path, &body).context("Failed to write cache file")?;
-    Ok(body)
+    let resp: RelationResponse =
+        serde_json::from_str(&json).context("Failed to parse Overpass response")?;
+    if let Some(remark) = resp.remark {
+        // Overpass reports timeouts and other failures in a 200 response; don't keep it
+        cache::invalidate(&cache_path);
+        bail!("Overpass query failed: {}", remark);
+    }
+
+    let mut store = ElementStore::default();
+    for element in resp.elements {
+        store.insert(element);
+    }
+    if !store.relations.contains_key(&relation_id) {
+        bail!("Relation {} not found in Overpass response", relation_id);
+    }
+
+    let memberships = store.way_memberships(relation_id, on_progress)?;
+    Ok(RelationWays {
+        ways: store.resolve_ways(&memberships),
+        failed: Vec::new(),
+    })
+}
+
+/// OSM elements held in memory by ID, for sources that deliver a whole relation tree at once.
+#[derive(Default)]
+struct ElementStore {
+    relations: HashMap<u64, Vec<RelationMember>>,
+    ways: HashMap<u64, (Vec<u64>, HashMap<String, String>)>,
+    nodes: HashMap<u64, Coord<f64>>,
 }
 
-/// Parse a `/relation/{id}/full.json` response into linestrings.
-pub fn parse_full_response(json: &str) -> Result<Vec<LineString<f64>>> {
+impl ElementStore {
+    fn insert(&mut self, element: Element) {
+        match element {
+            Element::Node {
+                id,
+                lat: Some(lat),
+                lon: Some(lon),
+            } => {
+                self.nodes.insert(id, Coord { x: lon, y: lat });
+            }
+            Element::Way {
+                id,
+                nodes: Some(node_refs),
+                tags,
+            } => {
+                self.ways.insert(id, (node_refs, tags.unwrap_or_default()));
+            }
+            Element::Relation {
+                id,
+                members: Some(members),
+                ..
+            } => {
+                self.relations.insert(id, members);
+            }
+            _ => {}
+        }
+    }
+
+    /// Walk the relation tree below `relation_id`, returning (relation path, way ID) pairs
+    /// in traversal and member order. Relations that aren't loaded are skipped.
+    fn way_memberships(
+        &self,
+        relation_id: u64,
+        on_progress: Option<&dyn Fn(FetchProgress)>,
+    ) -> Result<Vec<(Vec<u64>, u64)>> {
+        let (memberships, _) = walk_relation_tree(relation_id, 1, on_progress, |path| {
+            let members = self.relations.get(&path[path.len() - 1]).map_or(&[][..], Vec::as_slice);
+            let ways: Vec<(Vec<u64>, u64)> = members
+                .iter()
+                .filter(|m| m.member_type == "way")
+                .map(|m| (path.to_vec(), m.member_ref))
+                .collect();
+            Ok((ways, sub_relation_ids(members)))
+        })?;
+        Ok(memberships.into_iter().flatten().collect())
+    }
+
+    /// Load the coordinates of every node referenced by a loaded way.
+    fn load_way_nodes(&mut self, extract: &mut Extract) -> Result<()> {
+        let wanted_nodes: HashSet<u64> = self
+            .ways
+            .values()
+            .flat_map(|(refs, _)| refs.iter().copied())
+            .collect();
+        extract.scan(ElementType::Node, Some(&wanted_nodes), &mut |element| self.insert(element))
+    }
+
+    fn resolve_ways(&self, memberships: &[(Vec<u64>, u64)]) -> Vec<OsmWay> {
+        memberships
+            .iter()
+            .filter_map(|(path, way_id)| {
+                let (node_refs, tags) = self.ways.get(way_id)?;
+                resolve_way(*way_id, path, node_refs, tags, &self.nodes)
+            })
+            .collect()
+    }
+}
+
+fn sub_relation_ids(members: &[RelationMember]) -> Vec<u64> {
+    members
+        .iter()
+        .filter(|m| m.member_type == "relation")
+        .map(|m| m.member_ref)
+        .collect()
+}
+
+/// Parse a `/relation/{id}/full.json` response into ways.
+///
+/// `relation_path` runs from the top-level relation down to the relation that was fetched.
+pub fn parse_full_response(json: &str, relation_path: &[u64]) -> Result<Vec<OsmWay>> {
+    parse_full(json, relation_path).map(|(ways, _)| ways)
+}
+
+/// Parse a `/relation/{id}/full.json` response into ways and the fetched relation's
+/// member relation IDs.
+fn parse_full(json: &str, relation_path: &[u64]) -> Result<(Vec<OsmWay>, Vec<u64>)> {
     let resp: RelationResponse =
         serde_json::from_str(json).context("Failed to parse relation full JSON")?;
 
+    let relation_id = relation_path[relation_path.len() - 1];
+    let sub_relation_ids = resp
+        .elements
+        .iter()
+        .find_map(|e| match e {
+            Element::Relation {
+                id,
+                members: Some(members),
+                ..
+            } if *id == relation_id => Some(sub_relation_ids(members)),
+            _ => None,
+        })
+        .unwrap_or_default();
+
     // Build node lookup: id -> (lon, lat)
     let mut nodes: HashMap<u64, Coord<f64>> = HashMap::new();
     for element in &resp.elements {
-        if let Element::Node { id, lat, lon } = element {
-            if let (Some(lat), Some(lon)) = (lat, lon) {
-                nodes.insert(*id, Coord { x: *lon, y: *lat });
-            }
+        if let Element::Node { id, lat, lon } = element
+            && let (Some(lat), Some(lon)) = (lat, lon)
+        {
+            nodes.insert(*id, Coord { x: *lon, y: *lat });
         }
     }
 
     // Build linestrings from ways
-    let mut lines = Vec::new();
+    let mut ways = Vec::new();
     for element in &resp.elements {
         if let Element::Way {
+            id,
             nodes: Some(node_refs),
-            ..
+            tags,
         } = element
+            && let Some(way) = resolve_way(
+                *id,
+                relation_path,
+                node_refs,
+                tags.as_ref().unwrap_or(&HashMap::new()),
+                &nodes,
+            )
         {
-            let coords: Vec<Coord<f64>> = node_refs.iter().filter_map(|id| nodes.get(id)).copied().collect();
-            if coords.len() >= 2 {
-                lines.push(LineString::from(coords));
-            }
+            ways.push(way);
         }
     }
 
-    Ok(lines)
+    Ok((ways, sub_relation_ids))
+}
+
+/// Resolve a way's node references to coordinates. Ways with fewer than two known nodes are dropped.
+fn resolve_way(
+    id: u64,
+    relation_path: &[u64],
+    node_refs: &[u64],
+    tags: &HashMap<String, String>,
+    nodes: &HashMap<u64, Coord<f64>>,
+) -> Option<OsmWay> {
+    let (node_ids, coords): (Vec<u64>, Vec<Coord<f64>>) = node_refs
+        .iter()
+        .filter_map(|id| nodes.get(id).map(|c| (*id, *c)))
+        .unzip();
+    if coords.len() < 2 {
+        return None;
+    }
+    Some(OsmWay {
+        id,
+        relation_path: relation_path.to_vec(),
+        node_ids,
+        tags: tags.clone(),
+        geometry: LineString::from(coords),
+    })
 }
 
 #[cfg(test)]
@@ -176,7 +607,7 @@ mod tests {
                 {"type": "node", "id": 2, "lat": 34.001, "lon": -117.999},
                 {"type": "node", "id": 3, "lat": 34.002, "lon": -117.998},
                 {"type": "node", "id": 4, "lat": 34.003, "lon": -117.997},
-                {"type": "way", "id": 100, "nodes": [1, 2, 3], "tags": {}},
+                {"type": "way", "id": 100, "nodes": [1, 2, 3], "tags": {"highway": "path"}},
                 {"type": "way", "id": 101, "nodes": [3, 4], "tags": {}},
                 {"type": "relation", "id": 200, "members": [
                     {"type": "way", "ref": 100, "role": ""},
@@ -185,16 +616,25 @@ mod tests {
             ]
         }"#;
 
-        let lines = parse_full_response(json).unwrap();
-        assert_eq!(lines.len(), 2);
-        assert_eq!(lines[0].0.len(), 3);
-        assert_eq!(lines[1].0.len(), 2);
+        let ways = parse_full_response(json, &[200]).unwrap();
+        assert_eq!(ways.len(), 2);
+        assert_eq!(ways[0].geometry.0.len(), 3);
+        assert_eq!(ways[1].geometry.0.len(), 2);
 
         // Verify coordinates
-        assert_eq!(lines[0].0[0].x, -118.0);
-        assert_eq!(lines[0].0[0].y, 34.0);
-        assert_eq!(lines[1].0[1].x, -117.997);
-        assert_eq!(lines[1].0[1].y, 34.003);
+        assert_eq!(ways[0].geometry.0[0].x, -118.0);
+        assert_eq!(ways[0].geometry.0[0].y, 34.0);
+        assert_eq!(ways[1].geometry.0[1].x, -117.997);
+        assert_eq!(ways[1].geometry.0[1].y, 34.003);
+
+        // Verify identifiers and tags
+        assert_eq!(ways[0].id, 100);
+        assert_eq!(ways[0].relation_id(), Some(200));
+        assert_eq!(ways[0].node_ids, vec![1, 2, 3]);
+        assert_eq!(ways[0].tags.get("highway").map(String::as_str), Some("path"));
+        assert_eq!(ways[1].id, 101);
+        assert!(ways[1].tags.is_empty());
+        assert_eq!(ways[1].url(), "https://www.openstreetmap.org/way/101");
     }
 
     #[test]
@@ -207,15 +647,260 @@ mod tests {
             ]
         }"#;
 
-        let lines = parse_full_response(json).unwrap();
+        let ways = parse_full_response(json, &[200]).unwrap();
         // Way with only one resolvable node should be dropped (< 2 coords)
-        assert!(lines.is_empty());
+        assert!(ways.is_empty());
     }
 
     #[test]
     fn parse_full_response_empty() {
         let json = r#"{"version": "0.6", "elements": []}"#;
-        let lines = parse_full_response(json).unwrap();
-        assert!(lines.is_empty());
+        let ways = parse_full_response(json, &[200]).unwrap();
+        assert!(ways.is_empty());
+    }
+
+    /// Write `contents` to a uniquely named file in the system temp directory.
+    pub(super) fn temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("pct-diff-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn read_relation_ways_xml() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" lat="34.0" lon="-118.0"/>
+  <node id="2" lat="34.001" lon="-117.999"><tag k="natural" v="peak"/></node>
+  <node id="3" lat="34.002" lon="-117.998"/>
+  <node id="4" lat="34.003" lon="-117.997"/>
+  <node id="5" lat="35.0" lon="-119.0"/>
+  <node id="-1" lat="34.004" lon="-117.996"/>
+  <way id="100"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="path"/></way>
+  <way id="101"><nd ref="3"/><nd ref="4"/><nd ref="-1"/></way>
+  <way id="102"><nd ref="4"/><nd ref="5"/><tag k="highway" v="track"/></way>
+  <way id="-100"><nd ref="4"/><nd ref="-1"/></way>
+  <relation id="200">
+    <member type="way" ref="100" role=""/>
+    <member type="way" ref="101" role=""/>
+    <member type="way" ref="-100" role=""/>
+    <tag k="name" v="PCT Section A"/>
+  </relation>
+  <relation id="300">
+    <member type="relation" ref="200" role=""/>
+    <member type="relation" ref="999" role=""/>
+  </relation>
+</osm>"#;
+        let path = temp_file("read_relation_ways.osm", xml.as_bytes());
+
+        let ways = read_relation_ways(&path, 300, None).unwrap().ways;
+        std::fs::remove_file(&path).unwrap();
+
+        // Way 102 is not in the relation; sub-relation 999 is missing from the extract; way -100
+        // and node -1 were added in an editor and never uploaded
+        assert_eq!(ways.len(), 2);
+        assert_eq!(ways[0].id, 100);
+        assert_eq!(ways[0].relation_path, vec![300, 200]);
+        assert_eq!(ways[0].node_ids, vec![1, 2, 3]);
+        assert_eq!(ways[0].tags.get("highway").map(String::as_str), Some("path"));
+        assert_eq!(ways[1].id, 101);
+        assert_eq!(ways[1].node_ids, vec![3, 4]);
+        assert_eq!(ways[1].geometry.0[1], Coord { x: -117.997, y: 34.003 });
+    }
+
+    #[test]
+    fn read_trail_ways_filters_highways() {
+        let xml = r#"<osm version="0.6">
+  <node id="1" lat="34.0" lon="-118.0"/>
+  <node id="2" lat="34.001" lon="-117.999"/>
+  <node id="3" lat="34.002" lon="-117.998"/>
+  <way id="100"><nd ref="1"/><nd ref="2"/><tag k="highway" v="path"/></way>
+  <way id="101"><nd ref="2"/><nd ref="3"/><tag k="highway" v="footway"/></way>
+  <way id="102"><nd ref="1"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+  <way id="103"><nd ref="3"/><nd ref="1"/><tag k="highway" v="track"/></way>
+</osm>"#;
+        let path = temp_file("read_trail_ways.osm", xml.as_bytes());
+
+        let ways = read_trail_ways(&path, &HashSet::from([100])).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // 100 is a relation member and 102 is a road
+        let ids: Vec<u64> = ways.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![101, 103]);
+        assert_eq!(ways[0].relation_id(), None);
+        assert_eq!(ways[1].geometry.0[1], Coord { x: -118.0, y: 34.0 });
+    }
+
+    /// Serve canned raw HTTP responses on a local port, one per connection, returning the
+    /// base URL and a handle that yields the raw requests once all have been answered.
+    pub(super) fn mock_server(responses: Vec<String>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/interpreter", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0u8; 8192];
+                let n = stream.read(&mut buf).unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    /// Fetch options without rate limiting or retries, so tests don't wait.
+    pub(super) fn test_fetch_options() -> FetchOptions {
+        FetchOptions {
+            requests_per_second: 0.0,
+            max_retries: 0,
+            ..FetchOptions::default()
+        }
+    }
+
+    /// A `200 OK` JSON response with `body`.
+    pub(super) fn json_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn fetch_relation_ways_overpass_mock() {
+        let body = r#"{
+            "version": 0.6,
+            "elements": [
+                {"type": "relation", "id": 300, "members": [
+                    {"type": "relation", "ref": 200, "role": ""}
+                ], "tags": {"name": "Pacific Crest Trail"}},
+                {"type": "relation", "id": 200, "members": [
+                    {"type": "way", "ref": 100, "role": ""}
+                ], "tags": {}},
+                {"type": "way", "id": 100, "nodes": [1, 2], "tags": {"highway": "path"}},
+                {"type": "node", "id": 1, "lat": 34.0, "lon": -118.0},
+                {"type": "node", "id": 2, "lat": 34.001, "lon": -117.999}
+            ]
+        }"#;
+        let (url, server) = mock_server(vec![json_response(body)]);
+        let cache_dir = std::env::temp_dir().join(format!("pct-diff-{}-overpass-cache", std::process::id()));
+
+        let options = test_fetch_options();
+        let ways = fetch_relation_ways_overpass(300, &url, &cache_dir, &CachePolicy::default(), &options, None)
+            .unwrap()
+            .ways;
+        let request = server.join().unwrap().remove(0);
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert!(request.starts_with("GET /api/interpreter?data="), "unexpected request: {}", request);
+        assert!(request.contains("rel%28300%29"));
+        assert_eq!(ways.len(), 1);
+        assert_eq!(ways[0].id, 100);
+        assert_eq!(ways[0].relation_path, vec![300, 200]);
+        assert_eq!(ways[0].node_ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn fetch_relation_ways_overpass_remark() {
+        let body = r#"{"version": 0.6, "elements": [], "remark": "runtime error: Query timed out"}"#;
+        let (url, server) = mock_server(vec![json_response(body)]);
+        let cache_dir = std::env::temp_dir().join(format!("pct-diff-{}-overpass-remark", std::process::id()));
+
+        let result =
+            fetch_relation_ways_overpass(300, &url, &cache_dir, &CachePolicy::default(), &test_fetch_options(), None);
+        server.join().unwrap();
+        let cached = cache_dir.join("overpass_relation_300.json").exists();
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+        assert!(!cached, "Failed responses must not be cached");
+    }
+
+    #[test]
+    fn read_relation_ways_nested_with_cycle() {
+        // 300 -> {way 100, 200}; 200 -> {250, 300 (cycle)}; 250 -> {way 101, 200 (cycle)}
+        let xml = r#"<osm version="0.6">
+  <node id="1" lat="34.0" lon="-118.0"/>
+  <node id="2" lat="34.001" lon="-117.999"/>
+  <node id="3" lat="34.002" lon="-117.998"/>
+  <way id="100"><nd ref="1"/><nd ref="2"/></way>
+  <way id="101"><nd ref="2"/><nd ref="3"/></way>
+  <relation id="300">
+    <member type="way" ref="100" role=""/>
+    <member type="relation" ref="200" role=""/>
+  </relation>
+  <relation id="200">
+    <member type="relation" ref="250" role=""/>
+    <member type="relation" ref="300" role=""/>
+  </relation>
+  <relation id="250">
+    <member type="way" ref="101" role=""/>
+    <member type="relation" ref="200" role=""/>
+  </relation>
+</osm>"#;
+        let path = temp_file("nested_with_cycle.osm", xml.as_bytes());
+
+        let fetched = std::cell::RefCell::new(Vec::new());
+        let on_progress = |event| {
+            if let FetchProgress::SubRelationFetched(id) = event {
+                fetched.borrow_mut().push(id);
+            }
+        };
+        let ways = read_relation_ways(&path, 300, Some(&on_progress)).unwrap().ways;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(fetched.into_inner(), vec![300, 200, 250], "Each relation is visited once");
+        assert_eq!(ways.len(), 2);
+        assert_eq!(ways[0].id, 100, "Direct members of the top relation are included");
+        assert_eq!(ways[0].relation_path, vec![300]);
+        assert_eq!(ways[0].depth(), 1);
+        assert_eq!(ways[1].id, 101, "Ways two levels down are included");
+        assert_eq!(ways[1].relation_path, vec![300, 200, 250]);
+        assert_eq!(ways[1].depth(), 3);
+        assert_eq!(ways[1].relation_id(), Some(250));
+    }
+
+    #[test]
+    fn walk_relation_tree_reports_failures() {
+        // 1 -> {2, 3}; 2 -> {4}; 3 fails, so its member 5 is never reached
+        let children = |id: u64| -> Vec<u64> {
+            match id {
+                1 => vec![2, 3],
+                2 => vec![4],
+                3 => vec![5],
+                _ => vec![],
+            }
+        };
+        let (values, failed) = walk_relation_tree(1, 4, None, |path| {
+            let id = path[path.len() - 1];
+            if id == 3 {
+                bail!("HTTP 503");
+            }
+            Ok((id, children(id)))
+        })
+        .unwrap();
+
+        assert_eq!(values, vec![1, 2, 4]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].relation_path, vec![1, 3]);
+        assert!(failed[0].error.contains("503"));
+    }
+
+    #[test]
+    fn walk_relation_tree_root_failure_is_error() {
+        let result: Result<(Vec<()>, _)> = walk_relation_tree(1, 4, None, |_| bail!("HTTP 404"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn read_relation_ways_missing_relation() {
+        let path = temp_file("missing_relation.osm", br#"<osm version="0.6"></osm>"#);
+        let result = read_relation_ways(&path, 300, None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,311 @@
+use anyhow::{bail, Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::http::HttpClient;
+
+/// How cached OSM responses are reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// Reuse cached responses younger than the max-age; revalidate older ones.
+    #[default]
+    Normal,
+    /// Revalidate every cached response regardless of age.
+    Refresh,
+    /// Never touch the network; fail if a response isn't cached.
+    Offline,
+}
+
+/// Cache expiry settings for OSM responses.
+#[derive(Debug, Clone, Copy)]
+pub struct CachePolicy {
+    /// Cached responses older than this are revalidated. `None` keeps them forever.
+    pub max_age: Option<Duration>,
+    pub mode: CacheMode,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            max_age: Some(Duration::from_secs(24 * 60 * 60)),
+            mode: CacheMode::Normal,
+        }
+    }
+}
+
+/// Sidecar metadata stored next to each cached response as `<file>.meta.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheMetadata {
+    pub url: String,
+    /// When the response was last fetched or revalidated, in seconds since the Unix epoch.
+    pub fetched_at: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Version of the fetched relation, if the response carries it.
+    pub relation_version: Option<u64>,
+}
+
+impl CacheMetadata {
+    fn age(&self) -> Duration {
+        Duration::from_secs(now_secs().saturating_sub(self.fetched_at))
+    }
+}
+
+fn metadata_path(cache_path: &Path) -> PathBuf {
+    let mut name = cache_path.as_os_str().to_owned();
+    name.push(".meta.json");
+    PathBuf::from(name)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Read a cached response's metadata. Cache files written before metadata existed get
+/// their modification time as the fetch time.
+pub fn read_metadata(cache_path: &Path) -> Option<CacheMetadata> {
+    if let Ok(json) = std::fs::read_to_string(metadata_path(cache_path))
+        && let Ok(meta) = serde_json::from_str(&json)
+    {
+        return Some(meta);
+    }
+
+    let modified = std::fs::metadata(cache_path).ok()?.modified().ok()?;
+    Some(CacheMetadata {
+        url: String::new(),
+        fetched_at: modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        etag: None,
+        last_modified: None,
+        relation_version: None,
+    })
+}
+
+fn write_metadata(cache_path: &Path, meta: &CacheMetadata) -> Result<()> {
+    let json = serde_json::to_string_pretty(meta)?;
+    std::fs::write(metadata_path(cache_path), json).context("Failed to write cache metadata")
+}
+
+/// Remove a cached response and its metadata.
+pub(super) fn invalidate(cache_path: &Path) {
+    std::fs::remove_file(cache_path).ok();
+    std::fs::remove_file(metadata_path(cache_path)).ok();
+}
+
+/// Fetch a relation URL through the cache according to `policy`.
+///
+/// Expired entries are revalidated with `If-None-Match` / `If-Modified-Since`, so an
+/// unchanged relation costs a `304` rather than a full download. If revalidation fails,
+/// the stale copy is used with a warning.
+pub(super) fn fetch_cached(
+    client: &HttpClient,
+    url: &str,
+    cache_path: &Path,
+    relation_id: u64,
+    policy: &CachePolicy,
+) -> Result<String> {
+    let cached = cache_path
+        .exists()
+        .then(|| read_metadata(cache_path))
+        .flatten();
+
+    let Some(meta) = cached else {
+        if policy.mode == CacheMode::Offline {
+            bail!("{} is not cached and --offline was given", url);
+        }
+        return download(client, url, cache_path, relation_id, None);
+    };
+
+    let expired = policy.max_age.is_some_and(|max_age| meta.age() >= max_age);
+    let revalidate = match policy.mode {
+        CacheMode::Offline => false,
+        CacheMode::Refresh => true,
+        CacheMode::Normal => expired,
+    };
+    if !revalidate {
+        return std::fs::read_to_string(cache_path).context("Failed to read cache file");
+    }
+
+    match download(client, url, cache_path, relation_id, Some(&meta)) {
+        Ok(body) => Ok(body),
+        Err(e) => {
+            eprintln!("Warning: failed to revalidate {} ({:#}); using cached copy", url, e);
+            std::fs::read_to_string(cache_path).context("Failed to read cache file")
+        }
+    }
+}
+
+/// Fetch `url` (conditionally, if `previous` metadata is given) and update the cache.
+fn download(
+    client: &HttpClient,
+    url: &str,
+    cache_path: &Path,
+    relation_id: u64,
+    previous: Option<&CacheMetadata>,
+) -> Result<String> {
+    let mut headers = HeaderMap::new();
+    if let Some(meta) = previous {
+        if let Some(etag) = meta.etag.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = meta.last_modified.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = client.get(url, headers)?;
+
+    if response.status() == StatusCode::NOT_MODIFIED
+        && let Some(meta) = previous
+    {
+        let body = std::fs::read_to_string(cache_path).context("Failed to read cache file")?;
+        write_metadata(
+            cache_path,
+            &CacheMetadata {
+                url: url.to_string(),
+                fetched_at: now_secs(),
+                ..meta.clone()
+            },
+        )?;
+        return Ok(body);
+    }
+
+    let response = response.error_for_status()?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(ETAG);
+    let last_modified = header(LAST_MODIFIED);
+    let body = response.text()?;
+
+    std::fs::write(cache_path, &body).context("Failed to write cache file")?;
+    write_metadata(
+        cache_path,
+        &CacheMetadata {
+            url: url.to_string(),
+            fetched_at: now_secs(),
+            etag,
+            last_modified,
+            relation_version: relation_version(&body, relation_id),
+        },
+    )?;
+    Ok(body)
+}
+
+/// Find the `version` of `relation_id` in an OSM JSON response.
+fn relation_version(json: &str, relation_id: u64) -> Option<u64> {
+    let value: serde_json::Value = serde_json::from_str(json).ok()?;
+    value["elements"]
+        .as_array()?
+        .iter()
+        .find(|e| e["type"] == "relation" && e["id"] == relation_id)?["version"]
+        .as_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osm::tests::{mock_server, test_fetch_options};
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pct-diff-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn client() -> HttpClient {
+        HttpClient::new(&test_fetch_options()).unwrap()
+    }
+
+    const BODY: &str = r#"{"elements": [{"type": "relation", "id": 200, "version": 7, "members": []}]}"#;
+
+    #[test]
+    fn download_writes_metadata() {
+        let dir = cache_dir("cache-download");
+        let path = dir.join("relation_200_full.json");
+        let (url, server) = mock_server(vec![format!(
+            "HTTP/1.1 200 OK\r\nETag: \"abc\"\r\nContent-Length: {}\r\n\r\n{}",
+            BODY.len(),
+            BODY
+        )]);
+
+        let body = fetch_cached(&client(), &url, &path, 200, &CachePolicy::default()).unwrap();
+        server.join().unwrap();
+        let meta = read_metadata(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(body, BODY);
+        assert_eq!(meta.url, url);
+        assert_eq!(meta.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(meta.relation_version, Some(7));
+    }
+
+    #[test]
+    fn fresh_cache_skips_network() {
+        let dir = cache_dir("cache-fresh");
+        let path = dir.join("relation_200_full.json");
+        std::fs::write(&path, BODY).unwrap();
+
+        // Nothing listens on port 9, so any request would fail
+        let body = fetch_cached(&client(), "http://127.0.0.1:9/", &path, 200, &CachePolicy::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(body, BODY);
+    }
+
+    #[test]
+    fn expired_cache_revalidates_with_etag() {
+        let dir = cache_dir("cache-revalidate");
+        let path = dir.join("relation_200_full.json");
+        std::fs::write(&path, BODY).unwrap();
+        write_metadata(
+            &path,
+            &CacheMetadata {
+                url: String::new(),
+                fetched_at: 0,
+                etag: Some("\"abc\"".to_string()),
+                last_modified: None,
+                relation_version: Some(7),
+            },
+        )
+        .unwrap();
+        let (url, server) = mock_server(vec!["HTTP/1.1 304 Not Modified\r\n\r\n".to_string()]);
+
+        let body = fetch_cached(&client(), &url, &path, 200, &CachePolicy::default()).unwrap();
+        let requests = server.join().unwrap();
+        let meta = read_metadata(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(body, BODY);
+        assert!(requests[0].to_lowercase().contains("if-none-match: \"abc\""));
+        assert!(meta.fetched_at > 0, "Revalidation should refresh the fetch time");
+        assert_eq!(meta.relation_version, Some(7));
+    }
+
+    #[test]
+    fn offline_requires_cache() {
+        let dir = cache_dir("cache-offline");
+        let path = dir.join("relation_200_full.json");
+        let policy = CachePolicy {
+            max_age: Some(Duration::ZERO),
+            mode: CacheMode::Offline,
+        };
+
+        let missing = fetch_cached(&client(), "http://127.0.0.1:9/", &path, 200, &policy);
+        std::fs::write(&path, BODY).unwrap();
+        let expired = fetch_cached(&client(), "http://127.0.0.1:9/", &path, 200, &policy).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(missing.is_err());
+        assert_eq!(expired, BODY, "Offline mode uses cached copies even when expired");
+    }
+}
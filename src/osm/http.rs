@@ -0,0 +1,195 @@
+use anyhow::{bail, Result};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Concurrency, rate limiting and retry settings for OSM requests.
+#[derive(Debug, Clone, Copy)]
+pub struct FetchOptions {
+    /// Number of relations fetched concurrently.
+    pub workers: usize,
+    /// Upper bound on requests started per second, shared by all workers.
+    pub requests_per_second: f64,
+    /// Retries for 429, 5xx and connection errors before a request is given up on.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled on each subsequent one.
+    pub initial_backoff: Duration,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            requests_per_second: 2.0,
+            max_retries: 4,
+            initial_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Hands out evenly spaced request slots across threads.
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: f64) -> Self {
+        let interval = if requests_per_second > 0.0 {
+            Duration::from_secs_f64(1.0 / requests_per_second)
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Block until the caller may start a request.
+    fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        std::thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// A blocking HTTP client that rate-limits requests and retries transient failures.
+pub(super) struct HttpClient {
+    client: Client,
+    limiter: RateLimiter,
+    options: FetchOptions,
+}
+
+impl HttpClient {
+    pub(super) fn new(options: &FetchOptions) -> Result<Self> {
+        let client = Client::builder()
+            .user_agent("pct-diff/0.1 (PCT reroute detection tool)")
+            .build()?;
+        Ok(Self {
+            client,
+            limiter: RateLimiter::new(options.requests_per_second),
+            options: *options,
+        })
+    }
+
+    /// GET `url` with `headers`. Responses other than 429/5xx are returned as-is, so callers
+    /// can handle `304 Not Modified` and call `error_for_status` themselves.
+    pub(super) fn get(&self, url: &str, headers: HeaderMap) -> Result<Response> {
+        let mut backoff = self.options.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            self.limiter.acquire();
+            let result = self.client.get(url).headers(headers.clone()).send();
+
+            let retry_after = match &result {
+                Ok(response) if is_transient(response.status()) => Some(retry_after(response)),
+                Ok(_) => None,
+                Err(e) if e.is_connect() || e.is_timeout() => Some(None),
+                Err(_) => None,
+            };
+            let Some(retry_after) = retry_after else {
+                return Ok(result?);
+            };
+
+            if attempt >= self.options.max_retries {
+                return match result {
+                    Ok(response) => Ok(response.error_for_status()?),
+                    Err(e) => Err(e.into()),
+                };
+            }
+            if let Some(delay) = retry_after
+                && delay > Duration::from_secs(600)
+            {
+                bail!("Server asked to retry {} after {}s; giving up", url, delay.as_secs());
+            }
+
+            std::thread::sleep(retry_after.unwrap_or(backoff).max(backoff));
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// `Retry-After` in seconds, if the server sent one.
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osm::tests::{json_response, mock_server, test_fetch_options};
+
+    fn options() -> FetchOptions {
+        FetchOptions {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(10),
+            ..test_fetch_options()
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let (url, server) = mock_server(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_string(),
+            "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n".to_string(),
+            json_response("{}"),
+        ]);
+
+        let response = HttpClient::new(&options()).unwrap().get(&url, HeaderMap::new()).unwrap();
+        let requests = server.join().unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let unavailable = "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n".to_string();
+        let (url, server) = mock_server(vec![unavailable; 3]);
+
+        let result = HttpClient::new(&options()).unwrap().get(&url, HeaderMap::new());
+        server.join().unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (url, server) = mock_server(vec!["HTTP/1.1 410 Gone\r\nContent-Length: 0\r\n\r\n".to_string()]);
+
+        let response = HttpClient::new(&options()).unwrap().get(&url, HeaderMap::new()).unwrap();
+        assert_eq!(server.join().unwrap().len(), 1);
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    #[test]
+    fn rate_limiter_spaces_requests() {
+        let limiter = RateLimiter::new(20.0);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire();
+        }
+        // The first slot is immediate, the next two are 50ms apart
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,272 @@
+//! Reading `.osm.pbf` extracts (<https://wiki.openstreetmap.org/wiki/PBF_Format>) with
+//! the `osmpbf` crate.
+
+use anyhow::{Context, Result};
+use osmpbf::{BlobReader, BlobType, ByteOffset, Element as PbfElement, PrimitiveBlock, RelMemberType};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use super::{Element, ElementType, RelationMember};
+
+/// An open `.osm.pbf` file, with the range of element IDs in each data blob once it's been
+/// decoded.
+///
+/// Finding a relation's ways and nodes takes a scan per element type, but later scans only
+/// decompress the blobs whose ID ranges can hold an element being looked for, so most of a
+/// large extract is decoded once.
+pub(super) struct PbfExtract {
+    reader: BlobReader<BufReader<File>>,
+    blobs: Vec<BlobInfo>,
+}
+
+struct BlobInfo {
+    offset: ByteOffset,
+    /// Lowest and highest ID of the blob's nodes, ways and relations, by [`ElementType`].
+    ids: Option<[Option<RangeInclusive<i64>>; 3]>,
+}
+
+impl PbfExtract {
+    pub(super) fn open(path: &Path) -> Result<Self> {
+        let mut reader = BlobReader::seekable_from_path(path)?;
+        let mut blobs = Vec::new();
+        while let Some(header) = reader.next_header_skip_blob() {
+            let (header, offset) = header?;
+            // Header blobs hold no elements, and unknown blob types must be skipped per the spec
+            if header.blob_type() == BlobType::OsmData {
+                blobs.push(BlobInfo {
+                    offset: offset.context("PBF reader has no blob offsets")?,
+                    ids: None,
+                });
+            }
+        }
+        Ok(Self { reader, blobs })
+    }
+
+    /// Stream elements of `element_type` to `visit`; with `wanted`, only those with these IDs.
+    pub(super) fn scan(
+        &mut self,
+        element_type: ElementType,
+        wanted: Option<&HashSet<u64>>,
+        visit: &mut dyn FnMut(Element),
+    ) -> Result<()> {
+        let wanted_ids: Option<BTreeSet<i64>> = wanted.map(|ids| ids.iter().map(|&id| id as i64).collect());
+        for info in &mut self.blobs {
+            if let Some(ids) = &info.ids {
+                let Some(range) = &ids[element_type as usize] else {
+                    continue;
+                };
+                if wanted_ids.as_ref().is_some_and(|w| w.range(range.clone()).next().is_none()) {
+                    continue;
+                }
+            }
+
+            let block = self.reader.blob_from_offset(info.offset)?.to_primitiveblock()?;
+            info.ids.get_or_insert_with(|| id_ranges(&block));
+            block.for_each_element(|element| {
+                if let Some(element) = convert(element, element_type)
+                    && wanted.is_none_or(|w| w.contains(&element.id()))
+                {
+                    visit(element);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn id_ranges(block: &PrimitiveBlock) -> [Option<RangeInclusive<i64>>; 3] {
+    let mut ranges: [Option<RangeInclusive<i64>>; 3] = Default::default();
+    block.for_each_element(|element| {
+        let (element_type, id) = match element {
+            PbfElement::Node(node) => (ElementType::Node, node.id()),
+            PbfElement::DenseNode(node) => (ElementType::Node, node.id()),
+            PbfElement::Way(way) => (ElementType::Way, way.id()),
+            PbfElement::Relation(relation) => (ElementType::Relation, relation.id()),
+        };
+        let range = &mut ranges[element_type as usize];
+        *range = Some(range.as_ref().map_or(id..=id, |r| (*r.start()).min(id)..=(*r.end()).max(id)));
+    });
+    ranges
+}
+
+/// Convert an element of `element_type`; `None` for other types and for the negative IDs that
+/// editors such as JOSM give elements that haven't been uploaded, which can't be in a relation.
+fn convert(element: PbfElement, element_type: ElementType) -> Option<Element> {
+    let id = |id: i64| u64::try_from(id).ok();
+    match element {
+        PbfElement::Node(node) if element_type == ElementType::Node => Some(Element::Node {
+            id: id(node.id())?,
+            lat: Some(node.lat()),
+            lon: Some(node.lon()),
+        }),
+        PbfElement::DenseNode(node) if element_type == ElementType::Node => Some(Element::Node {
+            id: id(node.id())?,
+            lat: Some(node.lat()),
+            lon: Some(node.lon()),
+        }),
+        PbfElement::Way(way) if element_type == ElementType::Way => Some(Element::Way {
+            id: id(way.id())?,
+            nodes: Some(way.refs().filter_map(id).collect()),
+            tags: Some(way.tags().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+        }),
+        PbfElement::Relation(relation) if element_type == ElementType::Relation => Some(Element::Relation {
+            id: id(relation.id())?,
+            members: Some(
+                relation
+                    .members()
+                    .filter_map(|member| {
+                        Some(RelationMember {
+                            member_type: match member.member_type {
+                                RelMemberType::Node => "node",
+                                RelMemberType::Way => "way",
+                                RelMemberType::Relation => "relation",
+                            }
+                            .to_string(),
+                            member_ref: id(member.member_id)?,
+                            role: member.role().ok().map(str::to_string),
+                        })
+                    })
+                    .collect(),
+            ),
+            tags: Some(relation.tags().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osm::read_relation_ways;
+    use crate::osm::tests::temp_file;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn field_varint(field: u32, v: u64, out: &mut Vec<u8>) {
+        varint(u64::from(field) << 3, out);
+        varint(v, out);
+    }
+
+    fn field_bytes(field: u32, bytes: &[u8], out: &mut Vec<u8>) {
+        varint((u64::from(field) << 3) | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn packed(values: &[u64]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &v in values {
+            varint(v, &mut out);
+        }
+        out
+    }
+
+    fn zz(v: i64) -> u64 {
+        ((v << 1) ^ (v >> 63)) as u64
+    }
+
+    /// Delta- and zigzag-encode a list of values.
+    fn deltas(values: &[i64]) -> Vec<u64> {
+        let mut prev = 0;
+        values
+            .iter()
+            .map(|&v| {
+                let d = zz(v - prev);
+                prev = v;
+                d
+            })
+            .collect()
+    }
+
+    fn blob(blob_type: &str, data: &[u8], compress: bool, out: &mut Vec<u8>) {
+        let mut blob = Vec::new();
+        if compress {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            field_varint(2, data.len() as u64, &mut blob);
+            field_bytes(3, &encoder.finish().unwrap(), &mut blob);
+        } else {
+            field_bytes(1, data, &mut blob);
+        }
+
+        let mut header = Vec::new();
+        field_bytes(1, blob_type.as_bytes(), &mut header);
+        field_varint(3, blob.len() as u64, &mut header);
+
+        out.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&blob);
+    }
+
+    #[test]
+    fn read_relation_ways_pbf() {
+        // String table: 0 is reserved as the empty string
+        let mut strings = Vec::new();
+        for s in ["", "highway", "path", "outer"] {
+            field_bytes(1, s.as_bytes(), &mut strings);
+        }
+
+        // Nodes 1..=3 at (34.0, -118.0), (34.001, -117.999), (34.002, -117.998) in 100nd units
+        let mut dense = Vec::new();
+        field_bytes(1, &packed(&deltas(&[1, 2, 3])), &mut dense);
+        field_bytes(8, &packed(&deltas(&[340_000_000, 340_010_000, 340_020_000])), &mut dense);
+        field_bytes(9, &packed(&deltas(&[-1_180_000_000, -1_179_990_000, -1_179_980_000])), &mut dense);
+        let mut node_group = Vec::new();
+        field_bytes(2, &dense, &mut node_group);
+
+        let mut way = Vec::new();
+        field_varint(1, 100, &mut way);
+        field_bytes(2, &packed(&[1]), &mut way);
+        field_bytes(3, &packed(&[2]), &mut way);
+        field_bytes(8, &packed(&deltas(&[1, 2, 3])), &mut way);
+        let mut way_group = Vec::new();
+        field_bytes(3, &way, &mut way_group);
+
+        let mut sub = Vec::new();
+        field_varint(1, 200, &mut sub);
+        field_bytes(8, &packed(&[0]), &mut sub);
+        field_bytes(9, &packed(&deltas(&[100])), &mut sub);
+        field_bytes(10, &packed(&[1]), &mut sub);
+        let mut top = Vec::new();
+        field_varint(1, 300, &mut top);
+        field_bytes(8, &packed(&[3]), &mut top);
+        field_bytes(9, &packed(&deltas(&[200])), &mut top);
+        field_bytes(10, &packed(&[2]), &mut top);
+        let mut relation_group = Vec::new();
+        field_bytes(4, &sub, &mut relation_group);
+        field_bytes(4, &top, &mut relation_group);
+
+        let mut block = Vec::new();
+        field_bytes(1, &strings, &mut block);
+        field_bytes(2, &node_group, &mut block);
+        field_bytes(2, &way_group, &mut block);
+        field_bytes(2, &relation_group, &mut block);
+
+        let mut file = Vec::new();
+        blob("OSMHeader", b"", false, &mut file);
+        blob("OSMData", &block, true, &mut file);
+        let path = temp_file("read_relation_ways.osm.pbf", &file);
+
+        let ways = read_relation_ways(&path, 300, None).unwrap().ways;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ways.len(), 1);
+        assert_eq!(ways[0].id, 100);
+        assert_eq!(ways[0].relation_path, vec![300, 200]);
+        assert_eq!(ways[0].node_ids, vec![1, 2, 3]);
+        assert_eq!(ways[0].tags.get("highway").map(String::as_str), Some("path"));
+        let c = ways[0].geometry.0[1];
+        assert!((c.x - -117.999).abs() < 1e-9 && (c.y - 34.001).abs() < 1e-9);
+    }
+}
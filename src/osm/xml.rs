@@ -0,0 +1,131 @@
+use anyhow::{bail, Context, Result};
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::{Element, ElementType, RelationMember};
+
+/// Stream elements of `element_type` from an `.osm` XML file to `visit`.
+pub(super) fn scan(path: &Path, element_type: ElementType, visit: &mut dyn FnMut(Element)) -> Result<()> {
+    let mut reader = Reader::from_file(path)?;
+    let mut buf = Vec::new();
+    let mut current: Option<Element> = None;
+
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .with_context(|| format!("Invalid XML at byte {}", reader.buffer_position()))?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                match e.name().as_ref() {
+                    b"node" if element_type == ElementType::Node => {
+                        let attrs = attributes(e)?;
+                        if let Some(id) = parse_id(&attrs, "id")? {
+                            // Node tags aren't needed, so nodes are emitted as soon as they start
+                            visit(Element::Node {
+                                id,
+                                lat: attrs.get("lat").map(|v| v.parse()).transpose()?,
+                                lon: attrs.get("lon").map(|v| v.parse()).transpose()?,
+                            });
+                        }
+                    }
+                    b"way" if element_type == ElementType::Way => {
+                        // Ways that haven't been uploaded stay `None`, so their children are skipped
+                        let element = parse_id(&attributes(e)?, "id")?.map(|id| Element::Way {
+                            id,
+                            nodes: Some(Vec::new()),
+                            tags: Some(HashMap::new()),
+                        });
+                        match element {
+                            Some(element) if is_empty => visit(element),
+                            _ => current = element,
+                        }
+                    }
+                    b"relation" if element_type == ElementType::Relation => {
+                        let element = parse_id(&attributes(e)?, "id")?.map(|id| Element::Relation {
+                            id,
+                            members: Some(Vec::new()),
+                            tags: Some(HashMap::new()),
+                        });
+                        match element {
+                            Some(element) if is_empty => visit(element),
+                            _ => current = element,
+                        }
+                    }
+                    b"nd" => {
+                        if let Some(Element::Way {
+                            nodes: Some(nodes), ..
+                        }) = &mut current
+                        {
+                            nodes.extend(parse_id(&attributes(e)?, "ref")?);
+                        }
+                    }
+                    b"member" => {
+                        if let Some(Element::Relation {
+                            members: Some(members),
+                            ..
+                        }) = &mut current
+                        {
+                            let mut attrs = attributes(e)?;
+                            if let Some(member_ref) = parse_id(&attrs, "ref")? {
+                                members.push(RelationMember {
+                                    member_ref,
+                                    member_type: attrs.remove("type").unwrap_or_default(),
+                                    role: attrs.remove("role"),
+                                });
+                            }
+                        }
+                    }
+                    b"tag" => {
+                        if let Some(
+                            Element::Way { tags: Some(tags), .. } | Element::Relation { tags: Some(tags), .. },
+                        ) = &mut current
+                        {
+                            let mut attrs = attributes(e)?;
+                            if let (Some(k), Some(v)) = (attrs.remove("k"), attrs.remove("v")) {
+                                tags.insert(k, v);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(ref e) if matches!(e.name().as_ref(), b"way" | b"relation") => {
+                if let Some(element) = current.take() {
+                    visit(element);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    if current.is_some() {
+        bail!("Unexpected end of file inside an element");
+    }
+    Ok(())
+}
+
+fn attributes(e: &BytesStart) -> Result<HashMap<String, String>> {
+    let mut attrs = HashMap::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+        attrs.insert(key, attr.unescape_value()?.into_owned());
+    }
+    Ok(attrs)
+}
+
+/// Parse an element ID or reference; `None` for the negative IDs that editors such as JOSM give
+/// elements that haven't been uploaded, which can't be in a relation.
+fn parse_id(attrs: &HashMap<String, String>, name: &str) -> Result<Option<u64>> {
+    let id: i64 = attrs
+        .get(name)
+        .with_context(|| format!("Missing `{}` attribute", name))?
+        .parse()
+        .with_context(|| format!("Invalid `{}` attribute", name))?;
+    Ok(u64::try_from(id).ok())
+}
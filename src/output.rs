@@ -1,6 +1,17 @@
-use crate::compare::Divergence;
+use anyhow::{bail, Context, Result};
+use geo::{Coord, LineString};
+use geojson::feature::Id;
 use geojson::{Feature, FeatureCollection, GeoJson, Geometry, Value};
 use serde_json::json;
+use std::fmt::Write;
+
+use crate::compare::{
+    Divergence, DivergenceCategory, DivergenceKind, MatchTier, ProfileSample, RouteFinding, METERS_PER_MILE,
+};
+use crate::diff::{Change, ChangeKind};
+use crate::elevation::ElevationProfile;
+use crate::osm::way_url;
+use crate::pcta::AttributeValue;
 
 /// Convert divergences to a GeoJSON FeatureCollection.
 pub fn to_geojson(divergences: &[Divergence]) -> GeoJson {
@@ -13,29 +24,516 @@ pub fn to_geojson(divergences: &[Divergence]) -> GeoJson {
     })
 }
 
+fn linestring_geometry(ls: &LineString<f64>) -> Geometry {
+    let coords: Vec<Vec<f64>> = ls.0.iter().map(|c| vec![c.x, c.y]).collect();
+    Geometry::new(Value::LineString(coords))
+}
+
+/// Prefix of the properties holding the PCTA feature's own attributes.
+const PCTA_ATTRIBUTE_PREFIX: &str = "pcta:";
+
 fn divergence_to_feature(div: &Divergence) -> Feature {
-    let coords: Vec<Vec<f64>> = div
-        .pcta_segment
-        .0
-        .iter()
-        .map(|c| vec![c.x, c.y])
-        .collect();
+    let mut properties = json!({
+        "kind": div.kind.as_str(),
+        "category": div.category.as_str(),
+        "section_name": div.section_name,
+        "state": div.state,
+        "official": div.official,
+        "segment_id": div.segment_id,
+        "start_mile": (div.start_m / METERS_PER_MILE * 100.0).round() / 100.0,
+        "end_mile": (div.end_m / METERS_PER_MILE * 100.0).round() / 100.0,
+        "start_km": (div.start_m / 10.0).round() / 100.0,
+        "end_km": (div.end_m / 10.0).round() / 100.0,
+        "max_distance_m": (div.max_distance_m * 10.0).round() / 10.0,
+        "mean_distance_m": (div.mean_distance_m * 10.0).round() / 10.0,
+        "frechet_m": (div.frechet_m * 10.0).round() / 10.0,
+        "hausdorff_m": (div.hausdorff_m * 10.0).round() / 10.0,
+        "length_m": (div.length_m * 10.0).round() / 10.0,
+        "osm_way_ids": div.osm_way_ids,
+        "osm_way_urls": div.osm_way_ids.iter().map(|&id| way_url(id)).collect::<Vec<_>>(),
+        "osm_relation_ids": div.osm_relation_ids,
+        "match_tier": div.match_tier.map(|t| t.as_str()),
+        "other_way_ids": div.other_way_ids,
+        "other_way_urls": div.other_way_ids.iter().map(|&id| way_url(id)).collect::<Vec<_>>(),
+        "elevation_gain_m": div.elevation.map(|e| e.gain_m.round()),
+        "elevation_loss_m": div.elevation.map(|e| e.loss_m.round()),
+        "counterpart_gain_m": div.counterpart_elevation.map(|e| e.gain_m.round()),
+        "counterpart_loss_m": div.counterpart_elevation.map(|e| e.loss_m.round()),
+    })
+    .as_object()
+    .unwrap()
+    .clone();
+    for (name, value) in &div.pcta_attributes {
+        properties.insert(format!("{}{}", PCTA_ATTRIBUTE_PREFIX, name), attribute_to_json(value));
+    }
 
     Feature {
         bbox: None,
-        geometry: Some(Geometry::new(Value::LineString(coords))),
-        id: None,
-        properties: Some(
-            json!({
-                "section_name": div.section_name,
-                "max_distance_m": (div.max_distance_m * 10.0).round() / 10.0,
-                "mean_distance_m": (div.mean_distance_m * 10.0).round() / 10.0,
-                "length_m": (div.length_m * 10.0).round() / 10.0,
+        geometry: Some(linestring_geometry(&div.geometry)),
+        id: Some(Id::String(div.id.clone())),
+        properties: Some(properties),
+        foreign_members: None,
+    }
+}
+
+/// Read divergences back from a GeoJSON file written by [`to_geojson`].
+///
+/// Files written before the `kind` property existed are read as `missing_from_osm`.
+pub fn read_geojson(json: &str) -> Result<Vec<Divergence>> {
+    let GeoJson::FeatureCollection(collection) = json.parse::<GeoJson>()? else {
+        bail!("Expected a GeoJSON FeatureCollection");
+    };
+    collection
+        .features
+        .iter()
+        .enumerate()
+        .map(|(i, feature)| feature_to_divergence(feature).with_context(|| format!("Invalid feature {}", i)))
+        .collect()
+}
+
+fn feature_to_divergence(feature: &Feature) -> Result<Divergence> {
+    let geometry = match feature.geometry.as_ref().map(|g| &g.value) {
+        Some(Value::LineString(coords)) => coords
+            .iter()
+            .map(|c| match c.as_slice() {
+                [x, y, ..] => Ok(Coord { x: *x, y: *y }),
+                _ => bail!("Coordinate with fewer than two values"),
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into(),
+        _ => bail!("Expected a LineString geometry"),
+    };
+    let number = |name| feature.property(name).and_then(|v| v.as_f64()).unwrap_or(0.0);
+    let string = |name| feature.property(name).and_then(|v| v.as_str()).map(str::to_string);
+    let elevation = |gain, loss| -> Option<ElevationProfile> {
+        Some(ElevationProfile {
+            gain_m: feature.property(gain)?.as_f64()?,
+            loss_m: feature.property(loss)?.as_f64()?,
+        })
+    };
+    let ids = |name| -> Vec<u64> {
+        feature
+            .property(name)
+            .and_then(|v| v.as_array())
+            .map(|ids| ids.iter().filter_map(|id| id.as_u64()).collect())
+            .unwrap_or_default()
+    };
+
+    Ok(Divergence {
+        id: match &feature.id {
+            Some(Id::String(id)) => id.clone(),
+            Some(Id::Number(id)) => id.to_string(),
+            None => String::new(),
+        },
+        kind: match feature.property("kind").and_then(|v| v.as_str()) {
+            Some(kind) => kind.parse()?,
+            None => DivergenceKind::MissingFromOsm,
+        },
+        geometry,
+        section_name: string("section_name").unwrap_or_else(|| "Unknown".to_string()),
+        state: string("state"),
+        official: feature.property("official").and_then(|v| v.as_bool()),
+        segment_id: string("segment_id"),
+        pcta_attributes: feature
+            .properties_iter()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix(PCTA_ATTRIBUTE_PREFIX)?;
+                Some((name.to_string(), attribute_from_json(value)?))
             })
-            .as_object()
-            .unwrap()
-            .clone(),
-        ),
+            .collect(),
+        start_m: number("start_km") * 1000.0,
+        end_m: number("end_km") * 1000.0,
+        max_distance_m: number("max_distance_m"),
+        mean_distance_m: number("mean_distance_m"),
+        frechet_m: number("frechet_m"),
+        hausdorff_m: number("hausdorff_m"),
+        length_m: number("length_m"),
+        osm_way_ids: ids("osm_way_ids"),
+        osm_relation_ids: ids("osm_relation_ids"),
+        match_tier: feature
+            .property("match_tier")
+            .and_then(|v| v.as_str())
+            .map(str::parse::<MatchTier>)
+            .transpose()?,
+        other_way_ids: ids("other_way_ids"),
+        // Older outputs have no category; `reroute` is the least specific
+        category: match feature.property("category").and_then(|v| v.as_str()) {
+            Some(category) => category.parse()?,
+            None => DivergenceCategory::Reroute,
+        },
+        elevation: elevation("elevation_gain_m", "elevation_loss_m"),
+        counterpart_elevation: elevation("counterpart_gain_m", "counterpart_loss_m"),
+    })
+}
+
+fn attribute_to_json(value: &AttributeValue) -> serde_json::Value {
+    match value {
+        AttributeValue::Text(v) => json!(v),
+        AttributeValue::Integer(v) => json!(v),
+        AttributeValue::Real(v) => json!(v),
+        AttributeValue::List(values) => values.iter().map(attribute_to_json).collect(),
+    }
+}
+
+fn attribute_from_json(value: &serde_json::Value) -> Option<AttributeValue> {
+    match value {
+        serde_json::Value::String(v) => Some(AttributeValue::Text(v.clone())),
+        serde_json::Value::Number(v) => Some(match v.as_i64() {
+            Some(v) => AttributeValue::Integer(v),
+            None => AttributeValue::Real(v.as_f64()?),
+        }),
+        serde_json::Value::Array(values) => Some(AttributeValue::List(
+            values.iter().filter_map(attribute_from_json).collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Convert a distance profile to a GeoJSON FeatureCollection of points.
+pub fn profile_to_geojson(profile: &[ProfileSample]) -> GeoJson {
+    let round = |v: f64| (v * 10.0).round() / 10.0;
+    let features: Vec<Feature> = profile
+        .iter()
+        .map(|sample| Feature {
+            bbox: None,
+            geometry: Some(Geometry::new(Value::Point(vec![sample.coord.x, sample.coord.y]))),
+            id: None,
+            properties: Some(
+                json!({
+                    "kind": sample.kind.as_str(),
+                    "section_name": sample.section_name,
+                    "trail_mile": (sample.trail_m / METERS_PER_MILE * 1000.0).round() / 1000.0,
+                    "trail_km": sample.trail_m.round() / 1000.0,
+                    "distance_m": sample.distance_m.map(round),
+                    "osm_way_id": sample.osm_way_id,
+                    "bearing_diff_deg": sample.bearing_diff_deg.map(round),
+                    "divergent": sample.divergent,
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+            foreign_members: None,
+        })
+        .collect();
+
+    GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
         foreign_members: None,
+    })
+}
+
+/// Convert the gaps and overlaps found assembling the PCTA route to a GeoJSON FeatureCollection.
+pub fn route_findings_to_geojson(findings: &[RouteFinding]) -> GeoJson {
+    let features: Vec<Feature> = findings
+        .iter()
+        .map(|finding| Feature {
+            bbox: None,
+            geometry: Some(linestring_geometry(&finding.geometry)),
+            id: None,
+            properties: Some(
+                json!({
+                    "kind": finding.kind.as_str(),
+                    "from_section": finding.from_section,
+                    "to_section": finding.to_section,
+                    "length_m": (finding.length_m * 10.0).round() / 10.0,
+                    "trail_mile": (finding.trail_m / METERS_PER_MILE * 100.0).round() / 100.0,
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+            foreign_members: None,
+        })
+        .collect();
+
+    GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    })
+}
+
+/// Format a distance profile as CSV, one row per sample.
+pub fn profile_to_csv(profile: &[ProfileSample]) -> String {
+    let mut out =
+        "kind,section_name,trail_mile,trail_km,lon,lat,distance_m,osm_way_id,bearing_diff_deg,divergent\n".to_string();
+    let optional = |v: Option<f64>| v.map_or(String::new(), |v| format!("{:.1}", v));
+    for sample in profile {
+        writeln!(
+            out,
+            "{},{},{:.3},{:.3},{:.7},{:.7},{},{},{},{}",
+            sample.kind.as_str(),
+            csv_field(&sample.section_name),
+            sample.trail_m / METERS_PER_MILE,
+            sample.trail_m / 1000.0,
+            sample.coord.x,
+            sample.coord.y,
+            optional(sample.distance_m),
+            sample.osm_way_id.map_or(String::new(), |id| id.to_string()),
+            optional(sample.bearing_diff_deg),
+            sample.divergent
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Convert a change report to a GeoJSON FeatureCollection.
+///
+/// Each feature carries the current geometry and ID, or the old ones for resolved divergences.
+pub fn changes_to_geojson(changes: &[Change]) -> GeoJson {
+    let round = |v: f64| (v * 10.0).round() / 10.0;
+    let features: Vec<Feature> = changes
+        .iter()
+        .map(|change| {
+            let div = change.divergence();
+            Feature {
+                bbox: None,
+                geometry: Some(linestring_geometry(&div.geometry)),
+                id: Some(Id::String(div.id.clone())),
+                properties: Some(
+                    json!({
+                        "change": change.kind.as_str(),
+                        "kind": div.kind.as_str(),
+                        "category": div.category.as_str(),
+                        "section_name": div.section_name,
+                        "start_mile": (div.start_m / METERS_PER_MILE * 100.0).round() / 100.0,
+                        "end_mile": (div.end_m / METERS_PER_MILE * 100.0).round() / 100.0,
+                        "old_length_m": change.old.as_ref().map(|d| round(d.length_m)),
+                        "new_length_m": change.new.as_ref().map(|d| round(d.length_m)),
+                        "length_change_m": round(change.length_change_m()),
+                        "overlap_m": round(change.overlap_m),
+                        "max_distance_m": round(div.max_distance_m),
+                        "osm_way_ids": div.osm_way_ids,
+                    })
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+                ),
+                foreign_members: None,
+            }
+        })
+        .collect();
+
+    GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    })
+}
+
+/// Format a change report as a plain-text table with a summary line.
+pub fn changes_table(changes: &[Change]) -> String {
+    let count = |kind| changes.iter().filter(|c| c.kind == kind).count();
+    let mut out = format!(
+        "{} new, {} resolved, {} grown, {} shrunk, {} unchanged\n\n",
+        count(ChangeKind::New),
+        count(ChangeKind::Resolved),
+        count(ChangeKind::Grown),
+        count(ChangeKind::Shrunk),
+        count(ChangeKind::Unchanged)
+    );
+
+    let length = |d: Option<&Divergence>| d.map_or("-".to_string(), |d| format!("{:.0}", d.length_m));
+    let rows: Vec<[String; 9]> = changes
+        .iter()
+        .map(|c| {
+            let div = c.divergence();
+            [
+                c.kind.as_str().to_string(),
+                div.kind.as_str().to_string(),
+                div.section_name.clone(),
+                format!(
+                    "{:.1}-{:.1}",
+                    div.start_m / METERS_PER_MILE,
+                    div.end_m / METERS_PER_MILE
+                ),
+                length(c.old.as_ref()),
+                length(c.new.as_ref()),
+                format!("{:+.0}", c.length_change_m()),
+                format!("{:.0}", div.max_distance_m),
+                div.id.clone(),
+            ]
+        })
+        .collect();
+    let header = ["Change", "Kind", "Section", "Miles", "Old m", "New m", "Delta m", "Max off m", "ID"];
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut write_row = |cells: &[&str]| {
+        // Text columns are left-aligned, numbers right-aligned
+        for (i, (cell, width)) in cells.iter().zip(widths).enumerate() {
+            let sep = if i == 0 { "" } else { "  " };
+            if !(4..8).contains(&i) {
+                write!(out, "{}{:<width$}", sep, cell, width = width).unwrap();
+            } else {
+                write!(out, "{}{:>width$}", sep, cell, width = width).unwrap();
+            }
+        }
+        let trimmed = out.trim_end_matches(' ').len();
+        out.truncate(trimmed);
+        out.push('\n');
+    };
+    write_row(&header);
+    for row in &rows {
+        write_row(&row.each_ref().map(String::as_str));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::RouteFindingKind;
+    use crate::diff::diff_divergences;
+
+    fn divergence(kind: DivergenceKind, section_name: &str) -> Divergence {
+        Divergence {
+            id: format!("{}-{}", kind.as_str(), section_name.len()),
+            kind,
+            geometry: LineString::from(vec![(-118.0, 34.0), (-117.99, 34.0)]),
+            section_name: section_name.to_string(),
+            state: Some("CA".to_string()),
+            official: Some(true),
+            segment_id: Some("A-1".to_string()),
+            pcta_attributes: [
+                ("Section_Name".to_string(), AttributeValue::Text(section_name.to_string())),
+                ("Miles".to_string(), AttributeValue::Real(109.5)),
+                ("Segment".to_string(), AttributeValue::Integer(3)),
+                (
+                    "Tags".to_string(),
+                    AttributeValue::List(vec![AttributeValue::Text("closure".to_string())]),
+                ),
+            ]
+            .into(),
+            start_m: 16093.44,
+            end_m: 17014.0,
+            max_distance_m: 123.44,
+            mean_distance_m: 60.0,
+            frechet_m: 130.0,
+            hausdorff_m: 125.0,
+            length_m: 921.0,
+            osm_way_ids: vec![10, 11],
+            osm_relation_ids: vec![1],
+            match_tier: Some(MatchTier::OtherPath),
+            other_way_ids: vec![12],
+            category: DivergenceCategory::NotInRelation,
+            elevation: Some(ElevationProfile {
+                gain_m: 120.4,
+                loss_m: 15.0,
+            }),
+            counterpart_elevation: None,
+        }
+    }
+
+    #[test]
+    fn geojson_round_trip() {
+        let divs = vec![
+            divergence(DivergenceKind::MissingFromOsm, "CA Section A"),
+            divergence(DivergenceKind::StaleOsmWay, "CA Section B"),
+        ];
+        let json = serde_json::to_string(&to_geojson(&divs)).unwrap();
+
+        let read = read_geojson(&json).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].id, divs[1].id);
+        assert_eq!(read[1].kind, DivergenceKind::StaleOsmWay);
+        assert_eq!(read[1].section_name, "CA Section B");
+        assert_eq!(read[0].geometry, divs[0].geometry);
+        assert_eq!(read[0].max_distance_m, 123.4);
+        assert_eq!(read[0].start_m, 16090.0);
+        assert_eq!(read[0].osm_way_ids, vec![10, 11]);
+        assert_eq!(read[0].match_tier, Some(MatchTier::OtherPath));
+        assert_eq!(read[0].other_way_ids, vec![12]);
+        assert_eq!(read[0].category, DivergenceCategory::NotInRelation);
+        assert_eq!(read[0].elevation.map(|e| e.gain_m), Some(120.0));
+        assert_eq!(read[0].counterpart_elevation, None);
+        assert_eq!(read[0].state.as_deref(), Some("CA"));
+        assert_eq!(read[0].official, Some(true));
+        assert_eq!(read[0].segment_id.as_deref(), Some("A-1"));
+        assert_eq!(read[1].pcta_attributes, divs[1].pcta_attributes);
+    }
+
+    #[test]
+    fn profile_csv_rows() {
+        let sample = ProfileSample {
+            kind: DivergenceKind::MissingFromOsm,
+            section_name: "OR Section A, north".to_string(),
+            trail_m: 3218.688,
+            coord: Coord { x: -121.5, y: 45.7 },
+            distance_m: Some(12.34),
+            osm_way_id: Some(42),
+            bearing_diff_deg: None,
+            divergent: true,
+        };
+        let no_osm = ProfileSample {
+            distance_m: None,
+            osm_way_id: None,
+            divergent: false,
+            ..sample.clone()
+        };
+
+        let csv = profile_to_csv(&[sample, no_osm]);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("kind,section_name,trail_mile"));
+        assert_eq!(
+            lines[1],
+            "missing_from_osm,\"OR Section A, north\",2.000,3.219,-121.5000000,45.7000000,12.3,42,,true"
+        );
+        assert!(lines[2].ends_with(",45.7000000,,,,false"));
+    }
+
+    #[test]
+    fn route_finding_properties() {
+        let gap = RouteFinding {
+            kind: RouteFindingKind::Gap,
+            from_section: "CA Section A".to_string(),
+            to_section: "CA Section B".to_string(),
+            geometry: LineString::from(vec![(-118.0, 34.0), (-118.0, 34.001)]),
+            length_m: 111.19,
+            trail_m: 16_093.44,
+        };
+
+        let GeoJson::FeatureCollection(fc) = route_findings_to_geojson(&[gap]) else {
+            panic!("Expected a FeatureCollection");
+        };
+
+        let props = fc.features[0].properties.as_ref().unwrap();
+        assert_eq!(props["kind"], "gap");
+        assert_eq!(props["to_section"], "CA Section B");
+        assert_eq!(props["length_m"], 111.2);
+        assert_eq!(props["trail_mile"], 10.0);
+    }
+
+    #[test]
+    fn changes_table_lists_every_change() {
+        let old = vec![divergence(DivergenceKind::MissingFromOsm, "CA Section A")];
+        let new = vec![divergence(DivergenceKind::StaleOsmWay, "CA Section B")];
+        let table = changes_table(&diff_divergences(&old, &new, 25.0, 50.0));
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(lines[0], "1 new, 1 resolved, 0 grown, 0 shrunk, 0 unchanged");
+        assert!(lines[2].starts_with("Change    Kind"));
+        assert!(lines[3].starts_with("new       stale_osm_way     CA Section B  10.0-10.6"));
+        assert!(lines[4].starts_with("resolved  missing_from_osm  CA Section A  10.0-10.6"));
+        assert!(lines[4].ends_with("-921        123  missing_from_osm-12"));
     }
 }
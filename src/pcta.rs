@@ -1,56 +1,373 @@
-use anyhow::{Context, Result};
-use gdal::vector::LayerAccess;
-use gdal::Dataset;
+use anyhow::{bail, ensure, Context, Result};
+use gdal::spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef};
+use gdal::vector::{geometry_type_has_z, FieldValue, Layer, LayerAccess, OGRwkbGeometryType};
+use gdal::{Dataset, DatasetOptions, GdalOpenFlags};
 use geo::{Coord, LineString, MultiLineString};
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-use crate::compare::PctaSection;
+use crate::compare::{assemble_route, PctaSection, RouteFinding};
 
-/// Load PCTA sections from a GDB zip file using GDAL's /vsizip/ virtual filesystem.
-pub fn load_pcta_gdb(path: &Path) -> Result<Vec<PctaSection>> {
-    let vsi_path = format!("/vsizip/{}", path.canonicalize()?.display());
-    let dataset = Dataset::open(&vsi_path).context("Failed to open PCTA GDB via GDAL")?;
+/// Attributes tried, in order, for the section name when no field is chosen: the GDB's
+/// `Section_Name`, and the `name`/`Name` of GPX and KML features.
+const SECTION_NAME_FIELDS: &[&str] = &["Section_Name", "Section", "SECTION", "Name", "NAME", "name"];
+/// Attributes tried, in order, for the state when no field is chosen.
+const STATE_FIELDS: &[&str] = &["State", "STATE", "state"];
+/// Attributes tried, in order, for the official status when no field is chosen.
+const OFFICIAL_FIELDS: &[&str] = &["Official", "OFFICIAL", "official"];
 
-    let mut sections = Vec::new();
+/// A source of PCTA trail geometry.
+pub trait PctaSource {
+    /// Read every section, in whatever order the source holds them.
+    fn read_sections(&self) -> Result<Vec<PctaSection>>;
+}
+
+/// Which PCTA sections to compare.
+#[derive(Debug, Clone, Default)]
+pub struct SectionFilter {
+    /// Keep only sections marked as the official route, dropping alternates.
+    pub only_official: bool,
+    /// Keep only sections in these states (case-insensitive); all states if empty.
+    pub states: Vec<String>,
+}
+
+/// The PCTA sections stitched into one route, and the gaps and overlaps found doing so.
+#[derive(Debug, Clone)]
+pub struct PctaRoute {
+    pub sections: Vec<PctaSection>,
+    pub findings: Vec<RouteFinding>,
+    /// Sections dropped by [`SectionFilter::only_official`] because the source doesn't say
+    /// whether they are official.
+    pub unknown_official: Vec<String>,
+}
+
+/// Load PCTA sections from `source` that pass `filter`, assembled into a route from Mexico to
+/// Canada with ends within `snap_tolerance_m` snapped together.
+///
+/// Alternates are dropped before the route is assembled, so they don't count towards mileage or
+/// show up as gaps; sections in other states are dropped after, along with findings that don't
+/// touch the remaining sections, so mileage is still measured from the Mexican border.
+pub fn load_pcta(source: &dyn PctaSource, filter: &SectionFilter, snap_tolerance_m: f64) -> Result<PctaRoute> {
+    let mut sections = source.read_sections()?;
+    let mut unknown_official = Vec::new();
+    if filter.only_official {
+        ensure!(
+            sections.iter().any(|s| s.official.is_some()),
+            "No PCTA section has an official status to filter on; choose the field with --official-field"
+        );
+        sections.retain(|s| {
+            if s.official.is_none() {
+                unknown_official.push(s.section_name.clone());
+            }
+            s.official == Some(true)
+        });
+    }
+    let mut findings = assemble_route(&mut sections, snap_tolerance_m);
+    if !filter.states.is_empty() {
+        ensure!(
+            sections.iter().any(|s| s.state.is_some()),
+            "No PCTA section has a state to filter on; choose the field with --state-field"
+        );
+        sections.retain(|s| {
+            s.state
+                .as_ref()
+                .is_some_and(|state| filter.states.iter().any(|wanted| wanted.eq_ignore_ascii_case(state)))
+        });
+        findings.retain(|f| {
+            sections
+                .iter()
+                .any(|s| s.section_name == f.from_section || s.section_name == f.to_section)
+        });
+    }
+    Ok(PctaRoute {
+        sections,
+        findings,
+        unknown_official,
+    })
+}
+
+/// A layer of a dataset, by name or by index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayerSelector {
+    Name(String),
+    Index(usize),
+}
+
+impl LayerSelector {
+    fn open<'d>(&self, dataset: &'d Dataset) -> Result<Layer<'d>> {
+        let layer = match self {
+            LayerSelector::Name(name) => dataset.layer_by_name(name),
+            LayerSelector::Index(idx) => dataset.layer(*idx),
+        };
+        layer.or_else(|_| {
+            let names: Vec<String> = dataset.layers().map(|l| l.name()).collect();
+            bail!("No layer {} in the PCTA data; it has {:?}", self, names)
+        })
+    }
+}
+
+impl std::fmt::Display for LayerSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LayerSelector::Name(name) => write!(f, "{:?}", name),
+            LayerSelector::Index(idx) => write!(f, "#{}", idx),
+        }
+    }
+}
+
+impl std::str::FromStr for LayerSelector {
+    type Err = anyhow::Error;
+
+    /// A number selects a layer by index, anything else by name.
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.parse() {
+            Ok(idx) => LayerSelector::Index(idx),
+            Err(_) => LayerSelector::Name(s.to_string()),
+        })
+    }
+}
 
-    for layer_idx in 0..dataset.layer_count() {
-        let mut layer = dataset
-            .layer(layer_idx)
-            .context("Failed to get layer from GDB")?;
+/// Which features of a dataset become PCTA sections, and which of their attributes say what.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Layers to read; every layer if empty.
+    pub layers: Vec<LayerSelector>,
+    /// OGR SQL where-clause features must match, such as `State = 'CA'`.
+    pub where_clause: Option<String>,
+    /// The attribute holding the section name, rather than the first of [`SECTION_NAME_FIELDS`].
+    pub section_field: Option<String>,
+    /// The attribute holding the state, rather than the first of [`STATE_FIELDS`].
+    pub state_field: Option<String>,
+    /// The attribute saying whether a feature is the official route or an alternate (see
+    /// [`AttributeValue::as_bool`]), rather than the first of [`OFFICIAL_FIELDS`].
+    pub official_field: Option<String>,
+    /// The attribute holding a segment ID; none by default.
+    pub segment_id_field: Option<String>,
+}
+
+/// Any vector dataset GDAL can read: a File Geodatabase (zipped or not), Shapefile, GeoPackage,
+/// KML, GPX, GeoJSON, or a directory of them. GDAL picks the driver from the file.
+#[derive(Debug, Clone)]
+pub struct GdalSource {
+    pub path: PathBuf,
+    pub options: LoadOptions,
+}
+
+impl GdalSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            options: LoadOptions::default(),
+        }
+    }
+}
 
-        for feature in layer.features() {
-            let section_name = ["Section_Name", "Section", "SECTION", "Name", "NAME"]
+impl PctaSource for GdalSource {
+    fn read_sections(&self) -> Result<Vec<PctaSection>> {
+        let gdal_path = gdal_path(&self.path.canonicalize()?);
+        let options = DatasetOptions {
+            open_flags: GdalOpenFlags::GDAL_OF_VECTOR,
+            ..DatasetOptions::default()
+        };
+        let dataset = Dataset::open_ex(&gdal_path, options)
+            .with_context(|| format!("Failed to open {} via GDAL", self.path.display()))?;
+
+        let layers: Vec<Layer> = if self.options.layers.is_empty() {
+            dataset.layers().collect()
+        } else {
+            self.options
+                .layers
                 .iter()
-                .find_map(|&name| feature.field_index(name).ok())
-                .and_then(|idx| feature.field_as_string(idx).ok().flatten())
-                .unwrap_or_else(|| "Unknown".to_string());
+                .map(|selector| selector.open(&dataset))
+                .collect::<Result<_>>()?
+        };
+
+        let mut sections = Vec::new();
+        for mut layer in layers {
+            read_layer(&mut layer, &self.options, &mut sections)
+                .with_context(|| format!("Failed to read layer {:?} of {}", layer.name(), self.path.display()))?;
+        }
+        Ok(sections)
+    }
+}
+
+/// The path GDAL should open for `path`: archives are read through its virtual filesystems.
+fn gdal_path(path: &Path) -> String {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        format!("/vsizip/{}", path.display())
+    } else if name.ends_with(".gz") {
+        format!("/vsigzip/{}", path.display())
+    } else {
+        path.display().to_string()
+    }
+}
 
-            let Some(geom) = feature.geometry() else {
-                continue;
-            };
+/// WGS84 longitude/latitude, in that order (EPSG:4326 itself is latitude first), as `geo`
+/// coordinates are.
+pub(crate) fn wgs84() -> Result<SpatialRef> {
+    let mut srs = SpatialRef::from_epsg(4326)?;
+    srs.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    Ok(srs)
+}
 
-            let mls = gdal_geom_to_multilinestring(geom);
-            if mls.0.is_empty() {
-                continue;
+/// The transform from `layer`'s coordinate system to [`wgs84`], or `None` if it's already in it.
+fn to_wgs84(layer: &Layer) -> Result<Option<CoordTransform>> {
+    let Some(mut srs) = layer.spatial_ref() else {
+        bail!("Layer {:?} has no coordinate reference system", layer.name());
+    };
+    srs.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    let wgs84 = wgs84()?;
+    if srs == wgs84 {
+        return Ok(None);
+    }
+    let transform = CoordTransform::new(&srs, &wgs84)
+        .with_context(|| format!("Can't transform layer {:?} to WGS84", layer.name()))?;
+    Ok(Some(transform))
+}
+
+/// Read the line features of `layer` as sections, in WGS84. Features without line geometry,
+/// such as GPX waypoints, are skipped, as are layers with no geometry at all, such as the
+/// lookup tables of a File Geodatabase.
+fn read_layer(layer: &mut Layer, options: &LoadOptions, sections: &mut Vec<PctaSection>) -> Result<()> {
+    if layer.defn().geometry_type() == OGRwkbGeometryType::wkbNone {
+        return Ok(());
+    }
+    let transform = to_wgs84(layer)?;
+    let fields: Vec<String> = layer.defn().fields().map(|f| f.name()).collect();
+    let name_idx = field_index(&fields, options.section_field.as_deref(), SECTION_NAME_FIELDS)?;
+    let state_idx = field_index(&fields, options.state_field.as_deref(), STATE_FIELDS)?;
+    let official_idx = field_index(&fields, options.official_field.as_deref(), OFFICIAL_FIELDS)?;
+    let segment_id_idx = field_index(&fields, options.segment_id_field.as_deref(), &[])?;
+    if let Some(where_clause) = &options.where_clause {
+        layer
+            .set_attribute_filter(where_clause)
+            .with_context(|| format!("Invalid where-clause {:?}", where_clause))?;
+    }
+
+    for feature in layer.features() {
+        let attributes: BTreeMap<String, AttributeValue> = feature
+            .fields()
+            .filter_map(|(name, value)| Some((name, attribute_value(value?))))
+            .collect();
+        let attribute = |idx: Option<usize>| attributes.get(&fields[idx?]);
+        let text = |idx| attribute(idx).map(|v| v.to_string());
+        let section_name = text(name_idx).unwrap_or_else(|| "Unknown".to_string());
+        let state = text(state_idx);
+        let official = attribute(official_idx).and_then(AttributeValue::as_bool);
+        let segment_id = text(segment_id_idx);
+
+        let Some(geom) = feature.geometry() else {
+            continue;
+        };
+        let transformed;
+        let geom = match &transform {
+            Some(transform) => {
+                transformed = geom.transform(transform).context("Failed to reproject PCTA geometry")?;
+                &transformed
             }
+            None => geom,
+        };
 
-            sections.push(PctaSection {
-                section_name,
-                geometry: mls,
-            });
+        let mut lines = Vec::new();
+        collect_linestrings(geom, &mut lines);
+        if lines.is_empty() {
+            continue;
         }
+        let (linestrings, elevations): (Vec<_>, Vec<_>) = lines.into_iter().unzip();
+
+        sections.push(PctaSection {
+            section_name,
+            state,
+            official,
+            segment_id,
+            attributes,
+            geometry: MultiLineString::new(linestrings),
+            elevations_m: if geometry_type_has_z(geom.geometry_type()) {
+                elevations
+            } else {
+                Vec::new()
+            },
+            start_m: 0.0,
+            linestring_starts_m: Vec::new(),
+        });
     }
+    Ok(())
+}
 
-    Ok(sections)
+/// The value of a PCTA feature attribute. Dates are kept as text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Text(String),
+    Integer(i64),
+    Real(f64),
+    List(Vec<AttributeValue>),
 }
 
-fn gdal_geom_to_multilinestring(geom: &gdal::vector::Geometry) -> MultiLineString<f64> {
-    let mut linestrings = Vec::new();
-    collect_linestrings(geom, &mut linestrings);
-    MultiLineString::new(linestrings)
+impl AttributeValue {
+    /// The value as a yes/no flag: nonzero numbers, and text such as `Yes`, `Y`, `True` or
+    /// `Official`, are true; zero and text such as `No`, `False` or `Alternate` are false.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            AttributeValue::Integer(v) => Some(*v != 0),
+            AttributeValue::Real(v) => Some(*v != 0.0),
+            AttributeValue::Text(v) => match v.trim().to_ascii_lowercase().as_str() {
+                "yes" | "y" | "true" | "t" | "1" | "official" | "primary" => Some(true),
+                "no" | "n" | "false" | "f" | "0" | "alternate" | "alt" => Some(false),
+                _ => None,
+            },
+            AttributeValue::List(_) => None,
+        }
+    }
 }
 
-fn collect_linestrings(geom: &gdal::vector::Geometry, out: &mut Vec<LineString<f64>>) {
+impl std::fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AttributeValue::Text(v) => write!(f, "{}", v),
+            AttributeValue::Integer(v) => write!(f, "{}", v),
+            AttributeValue::Real(v) => write!(f, "{}", v),
+            AttributeValue::List(values) => {
+                for (i, v) in values.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, v)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn attribute_value(value: FieldValue) -> AttributeValue {
+    let list = |values: Vec<AttributeValue>| AttributeValue::List(values);
+    match value {
+        FieldValue::IntegerValue(v) => AttributeValue::Integer(v.into()),
+        FieldValue::Integer64Value(v) => AttributeValue::Integer(v),
+        FieldValue::RealValue(v) => AttributeValue::Real(v),
+        FieldValue::StringValue(v) => AttributeValue::Text(v),
+        FieldValue::DateValue(v) => AttributeValue::Text(v.to_string()),
+        FieldValue::DateTimeValue(v) => AttributeValue::Text(v.to_string()),
+        FieldValue::IntegerListValue(v) => list(v.into_iter().map(|v| AttributeValue::Integer(v.into())).collect()),
+        FieldValue::Integer64ListValue(v) => list(v.into_iter().map(AttributeValue::Integer).collect()),
+        FieldValue::RealListValue(v) => list(v.into_iter().map(AttributeValue::Real).collect()),
+        FieldValue::StringListValue(v) => list(v.into_iter().map(AttributeValue::Text).collect()),
+    }
+}
+
+/// The index in `fields` of `chosen`, which must exist, or else of the first of `defaults` that
+/// does.
+fn field_index(fields: &[String], chosen: Option<&str>, defaults: &[&str]) -> Result<Option<usize>> {
+    let find = |name: &str| fields.iter().position(|f| f == name);
+    match chosen {
+        Some(name) => match find(name) {
+            Some(idx) => Ok(Some(idx)),
+            None => bail!("No field {:?}; the fields are {:?}", name, fields),
+        },
+        None => Ok(defaults.iter().find_map(|&name| find(name))),
+    }
+}
+
+/// Collect the linestrings in `geom`, each with the Z value of every vertex (0 if it has none).
+fn collect_linestrings(geom: &gdal::vector::Geometry, out: &mut Vec<(LineString<f64>, Vec<f64>)>) {
     let name = geom.geometry_name();
     if name.starts_with("LINESTRING") {
         if let Some(ls) = extract_linestring(geom) {
@@ -68,18 +385,198 @@ fn collect_linestrings(geom: &gdal::vector::Geometry, out: &mut Vec<LineString<f
     }
 }
 
-fn extract_linestring(geom: &gdal::vector::Geometry) -> Option<LineString<f64>> {
+fn extract_linestring(geom: &gdal::vector::Geometry) -> Option<(LineString<f64>, Vec<f64>)> {
     let n = geom.point_count();
     if n < 2 {
         return None;
     }
 
-    let coords: Vec<Coord<f64>> = (0..n as i32)
+    let (coords, elevations): (Vec<Coord<f64>>, Vec<f64>) = (0..n as i32)
         .map(|i| {
-            let (x, y, _z) = geom.get_point(i);
-            Coord { x, y }
+            let (x, y, z) = geom.get_point(i);
+            (Coord { x, y }, z)
         })
-        .collect();
+        .unzip();
+
+    Some((LineString::from(coords), elevations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::test_support::make_section;
+
+    #[test]
+    fn archives_use_virtual_filesystems() {
+        assert_eq!(gdal_path(Path::new("/data/Full_PCT.gdb.zip")), "/vsizip//data/Full_PCT.gdb.zip");
+        assert_eq!(gdal_path(Path::new("/data/reroute.GEOJSON.gz")), "/vsigzip//data/reroute.GEOJSON.gz");
+        assert_eq!(gdal_path(Path::new("/data/reroute.kml")), "/data/reroute.kml");
+        assert_eq!(gdal_path(Path::new("/data/Full_PCT.gdb")), "/data/Full_PCT.gdb");
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    /// The fixtures' lines, in longitude/latitude.
+    const CAMPO: [(f64, f64); 3] = [(-116.467, 32.5897), (-116.465, 32.6), (-116.46, 32.61)];
+    const BRIDGE_OF_THE_GODS: [(f64, f64); 3] = [(-121.8, 45.66), (-121.79, 45.68), (-121.78, 45.7)];
 
-    Some(LineString::from(coords))
+    fn assert_coords(section: &PctaSection, expected: &[(f64, f64)]) {
+        let coords = &section.geometry.0[0].0;
+        assert_eq!(coords.len(), expected.len());
+        for (c, &(x, y)) in coords.iter().zip(expected) {
+            // ~1m; NAD83 and WGS84 differ by about that much
+            assert!((c.x - x).abs() < 1e-5 && (c.y - y).abs() < 1e-5, "{:?} != {:?}", c, (x, y));
+        }
+    }
+
+    #[test]
+    fn reads_projected_utm() {
+        let source = GdalSource::new(fixture("nad83_utm11n.geojson"));
+        let sections = load_pcta(&source, &SectionFilter::default(), 5.0).unwrap().sections;
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].section_name, "CA Section A");
+        assert_coords(&sections[0], &CAMPO);
+        assert_eq!(sections[0].elevations_m, vec![vec![1000.0, 1010.0, 1025.0]]);
+    }
+
+    #[test]
+    fn reads_web_mercator() {
+        let source = GdalSource::new(fixture("web_mercator.geojson"));
+        let sections = load_pcta(&source, &SectionFilter::default(), 5.0).unwrap().sections;
+        assert_eq!(sections[0].section_name, "WA Section L");
+        assert_coords(&sections[0], &BRIDGE_OF_THE_GODS);
+        assert!(sections[0].elevations_m.is_empty());
+    }
+
+    #[test]
+    fn reads_gpx_tracks() {
+        let source = GdalSource {
+            options: LoadOptions {
+                layers: vec![LayerSelector::Name("tracks".to_string())],
+                ..LoadOptions::default()
+            },
+            ..GdalSource::new(fixture("reroute.gpx"))
+        };
+        let sections = load_pcta(&source, &SectionFilter::default(), 5.0).unwrap().sections;
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].section_name, "Interim reroute");
+        assert_coords(&sections[0], &CAMPO);
+    }
+
+    #[test]
+    fn reads_selected_fields_and_features() {
+        let source = GdalSource {
+            options: LoadOptions {
+                layers: vec![LayerSelector::Index(0)],
+                where_clause: Some("Section_Name = 'CA Section B' AND Official = 'Yes'".to_string()),
+                section_field: Some("Section_Name".to_string()),
+                state_field: Some("Region".to_string()),
+                official_field: None,
+                segment_id_field: Some("Segment".to_string()),
+            },
+            ..GdalSource::new(fixture("sections.geojson"))
+        };
+        let sections = load_pcta(&source, &SectionFilter::default(), 5.0).unwrap().sections;
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].section_name, "CA Section B");
+        assert_eq!(sections[0].state.as_deref(), Some("CA"));
+        assert_eq!(sections[0].segment_id.as_deref(), Some("B-2"));
+        assert_eq!(sections[0].official, Some(true));
+        assert_eq!(sections[0].attributes["Official"], AttributeValue::Text("Yes".to_string()));
+        assert_eq!(sections[0].attributes["Order"], AttributeValue::Integer(2));
+
+        let missing = GdalSource {
+            options: LoadOptions {
+                section_field: Some("Nope".to_string()),
+                ..LoadOptions::default()
+            },
+            ..GdalSource::new(fixture("sections.geojson"))
+        };
+        assert!(load_pcta(&missing, &SectionFilter::default(), 5.0).is_err());
+    }
+
+    #[test]
+    fn skips_non_spatial_layers() {
+        // The sections next to a table of regions, which has no geometry or coordinate system
+        let sections = load_pcta(&GdalSource::new(fixture("with_table.vrt")), &SectionFilter::default(), 5.0)
+            .unwrap()
+            .sections;
+        assert_eq!(sections.len(), 3);
+    }
+
+    #[test]
+    fn layer_selectors() {
+        assert_eq!("2".parse::<LayerSelector>().unwrap(), LayerSelector::Index(2));
+        assert_eq!("PCT_Tracks".parse::<LayerSelector>().unwrap(), LayerSelector::Name("PCT_Tracks".to_string()));
+    }
+
+    /// Sections already in memory.
+    struct Sections(Vec<PctaSection>);
+
+    impl PctaSource for Sections {
+        fn read_sections(&self) -> Result<Vec<PctaSection>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn section(name: &str, state: &str, official: bool, lat: f64) -> PctaSection {
+        PctaSection {
+            state: Some(state.to_string()),
+            official: Some(official),
+            ..make_section(name, vec![(-120.0, lat), (-120.0, lat + 0.1)])
+        }
+    }
+
+    #[test]
+    fn filters_sections() {
+        let source = Sections(vec![
+            section("CA Section R", "CA", true, 41.9),
+            section("OR Section A", "OR", true, 42.0),
+            section("OR Section A alternate", "OR", false, 41.95),
+            section("OR Section B", "OR", true, 42.1),
+            PctaSection {
+                official: None,
+                ..section("OR Section C", "OR", true, 42.2)
+            },
+        ]);
+        let filter = SectionFilter {
+            only_official: true,
+            states: vec!["or".to_string()],
+        };
+
+        let route = load_pcta(&source, &filter, 5.0).unwrap();
+        let sections = route.sections;
+        assert_eq!(route.unknown_official, vec!["OR Section C"], "Sections of unknown status are reported");
+
+        let names: Vec<&str> = sections.iter().map(|s| s.section_name.as_str()).collect();
+        assert_eq!(names, vec!["OR Section A", "OR Section B"]);
+        // Mileage still starts at the border, and doesn't count the alternate
+        let degree_m = 111_195.0;
+        assert!((sections[0].start_m - 0.1 * degree_m).abs() < 1.0, "{}", sections[0].start_m);
+        assert!((sections[1].start_m - 0.2 * degree_m).abs() < 1.0, "{}", sections[1].start_m);
+
+        let unknown = Sections(vec![PctaSection {
+            state: None,
+            ..section("Somewhere", "", true, 40.0)
+        }]);
+        assert!(load_pcta(&unknown, &filter, 5.0).is_err());
+    }
+
+    #[test]
+    fn official_flags() {
+        let flag = |v: AttributeValue| v.as_bool();
+        assert_eq!(flag(AttributeValue::Text("Yes".to_string())), Some(true));
+        assert_eq!(flag(AttributeValue::Text(" alternate ".to_string())), Some(false));
+        assert_eq!(flag(AttributeValue::Integer(0)), Some(false));
+        assert_eq!(flag(AttributeValue::Text("Closed".to_string())), None);
+    }
+
+    #[test]
+    fn rejects_missing_crs() {
+        let source = GdalSource::new(fixture("no_crs.csv"));
+        let err = load_pcta(&source, &SectionFilter::default(), 5.0).unwrap_err();
+        assert!(format!("{:#}", err).contains("no coordinate reference system"), "{:#}", err);
+    }
 }